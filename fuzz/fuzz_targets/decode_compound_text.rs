@@ -0,0 +1,10 @@
+//! Decode arbitrary strings sent by an IME server that selected Compound Text.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use xcb_imdkit::Encoding;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Encoding::CompoundText.decode(data);
+});
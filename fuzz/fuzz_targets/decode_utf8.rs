@@ -0,0 +1,14 @@
+//! Decode arbitrary strings sent by an IME server that selected UTF-8.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use xcb_imdkit::Encoding;
+
+fuzz_target!(|data: &[u8]| {
+    let text = Encoding::Utf8String.decode(data);
+    // valid UTF-8 is passed on unchanged
+    if let Ok(valid) = std::str::from_utf8(data) {
+        assert_eq!(text, valid);
+    }
+});
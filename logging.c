@@ -1,8 +1,12 @@
 #include <stdarg.h>
 #include <stdio.h>
 
+/* Implemented in src/lib.rs, expects an already formatted, NUL-terminated message. */
 extern void rust_log(const char* msg);
 
+/* Log handler passed to xcb-imdkit. The message is formatted here, including all variadic
+ * arguments, so no format arguments ever cross into Rust. Messages longer than the buffer are
+ * truncated; vsnprintf always NUL-terminates the buffer. */
 void xcb_log_wrapper(const char *fmt, ...) {
     char buf[512];
     va_list argp;
@@ -6,6 +6,11 @@
 
 include!("bindings.rs");
 
+extern "C" {
+    pub fn malloc(size: usize) -> *mut ::std::os::raw::c_void;
+    pub fn xcb_flush(c: *mut xcb_connection_t) -> ::std::os::raw::c_int;
+}
+
 impl Default for xcb_xim_im_callback {
     fn default() -> Self {
         Self {
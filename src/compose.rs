@@ -0,0 +1,258 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Keysyms that can be referred to by name in a compose table, besides single characters and
+/// Unicode keysyms (`U00E4`).
+const KEYSYM_NAMES: &[(&str, u32)] = &[
+    ("space", 0x20),
+    ("exclam", 0x21),
+    ("quotedbl", 0x22),
+    ("numbersign", 0x23),
+    ("dollar", 0x24),
+    ("percent", 0x25),
+    ("ampersand", 0x26),
+    ("apostrophe", 0x27),
+    ("parenleft", 0x28),
+    ("parenright", 0x29),
+    ("asterisk", 0x2a),
+    ("plus", 0x2b),
+    ("comma", 0x2c),
+    ("minus", 0x2d),
+    ("period", 0x2e),
+    ("slash", 0x2f),
+    ("colon", 0x3a),
+    ("semicolon", 0x3b),
+    ("less", 0x3c),
+    ("equal", 0x3d),
+    ("greater", 0x3e),
+    ("question", 0x3f),
+    ("at", 0x40),
+    ("bracketleft", 0x5b),
+    ("backslash", 0x5c),
+    ("bracketright", 0x5d),
+    ("asciicircum", 0x5e),
+    ("underscore", 0x5f),
+    ("grave", 0x60),
+    ("braceleft", 0x7b),
+    ("bar", 0x7c),
+    ("braceright", 0x7d),
+    ("asciitilde", 0x7e),
+    ("nobreakspace", 0xa0),
+    ("exclamdown", 0xa1),
+    ("cent", 0xa2),
+    ("sterling", 0xa3),
+    ("currency", 0xa4),
+    ("yen", 0xa5),
+    ("brokenbar", 0xa6),
+    ("section", 0xa7),
+    ("diaeresis", 0xa8),
+    ("copyright", 0xa9),
+    ("ordfeminine", 0xaa),
+    ("guillemotleft", 0xab),
+    ("notsign", 0xac),
+    ("hyphen", 0xad),
+    ("registered", 0xae),
+    ("macron", 0xaf),
+    ("degree", 0xb0),
+    ("plusminus", 0xb1),
+    ("twosuperior", 0xb2),
+    ("threesuperior", 0xb3),
+    ("acute", 0xb4),
+    ("mu", 0xb5),
+    ("paragraph", 0xb6),
+    ("periodcentered", 0xb7),
+    ("cedilla", 0xb8),
+    ("onesuperior", 0xb9),
+    ("masculine", 0xba),
+    ("guillemotright", 0xbb),
+    ("onequarter", 0xbc),
+    ("onehalf", 0xbd),
+    ("threequarters", 0xbe),
+    ("questiondown", 0xbf),
+    ("Multi_key", 0xff20),
+    ("dead_grave", 0xfe50),
+    ("dead_acute", 0xfe51),
+    ("dead_circumflex", 0xfe52),
+    ("dead_tilde", 0xfe53),
+    ("dead_macron", 0xfe54),
+    ("dead_breve", 0xfe55),
+    ("dead_abovedot", 0xfe56),
+    ("dead_diaeresis", 0xfe57),
+    ("dead_abovering", 0xfe58),
+    ("dead_doubleacute", 0xfe59),
+    ("dead_caron", 0xfe5a),
+    ("dead_cedilla", 0xfe5b),
+    ("dead_ogonek", 0xfe5c),
+    ("dead_iota", 0xfe5d),
+    ("dead_voiced_sound", 0xfe5e),
+    ("dead_semivoiced_sound", 0xfe5f),
+    ("dead_belowdot", 0xfe60),
+    ("dead_hook", 0xfe61),
+    ("dead_horn", 0xfe62),
+    ("dead_stroke", 0xfe63),
+];
+
+fn keysym_from_name(name: &str) -> Option<u32> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        // keysyms of printable ASCII characters equal their code
+        return if c.is_ascii_graphic() {
+            Some(c as u32)
+        } else {
+            None
+        };
+    }
+    if let Some(hex) = name.strip_prefix("0x") {
+        return u32::from_str_radix(hex, 16).ok();
+    }
+    if let Some(hex) = name.strip_prefix('U') {
+        if let Ok(code) = u32::from_str_radix(hex, 16) {
+            // Latin-1 characters have legacy keysyms equal to their code
+            return Some(
+                if (0x20..0x7f).contains(&code) || (0xa0..0x100).contains(&code) {
+                    code
+                } else {
+                    0x0100_0000 | code
+                },
+            );
+        }
+    }
+    KEYSYM_NAMES
+        .iter()
+        .find(|&&(known, _)| known == name)
+        .map(|&(_, keysym)| keysym)
+}
+
+/// Parse the quoted result string of a compose sequence, return it and the rest of the line.
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut res = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((res, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => res.push('\n'),
+                't' => res.push('\t'),
+                c @ '0'..='7' => {
+                    let mut code = c.to_digit(8)?;
+                    for _ in 0..2 {
+                        match chars.clone().next() {
+                            Some((_, c @ '0'..='7')) => {
+                                chars.next();
+                                code = code * 8 + c.to_digit(8)?;
+                            }
+                            _ => break,
+                        }
+                    }
+                    res.push(std::char::from_u32(code)?);
+                }
+                c => res.push(c),
+            },
+            c => res.push(c),
+        }
+    }
+    None
+}
+
+/// Parse one line of a compose file into the keysym sequence and its result.
+fn parse_line(line: &str) -> Option<(Vec<u32>, String)> {
+    let (keys, result) = line.split_once(':')?;
+    let sequence = keys
+        .split('>')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(|key| keysym_from_name(key.strip_prefix('<')?))
+        .collect::<Option<Vec<_>>>()?;
+    if sequence.is_empty() {
+        return None;
+    }
+    let (text, _) = parse_string(result.trim_start())?;
+    Some((sequence, text))
+}
+
+/// Table of compose sequences in the format of XCompose files.
+///
+/// Each line maps a sequence of keysyms to the text it produces, e.g.
+/// `<Multi_key> <a> <e> : "æ" ae`. Comments start with `#`. Keysyms can be named as single
+/// characters, Unicode keysyms like `<U00E4>`, hexadecimal values like `<0xfe51>` or by the
+/// names of punctuation, Latin-1 symbols, `Multi_key` and dead keys. Lines using other keysym
+/// names as well as `include` statements are ignored.
+///
+/// Set the table with [`ImeClient::set_compose_table`](crate::ImeClient::set_compose_table).
+#[derive(Debug, Clone, Default)]
+pub struct ComposeTable {
+    sequences: HashMap<Vec<u32>, String>,
+    prefixes: HashSet<Vec<u32>>,
+}
+
+/// Result of feeding a keysym into a [`ComposeTable`].
+pub(crate) enum ComposeResult {
+    /// The keysym is not part of a compose sequence.
+    Ignored,
+    /// The keysym continued or cancelled a compose sequence.
+    Consumed,
+    /// The keysym completed a compose sequence.
+    Composed(String),
+}
+
+impl ComposeTable {
+    /// Parse a compose table from the contents of a compose file.
+    pub fn parse(source: &str) -> Self {
+        let mut table = Self::default();
+        for line in source.lines() {
+            let line = line.trim_start();
+            if line.starts_with('#') || line.starts_with("include") {
+                continue;
+            }
+            if let Some((sequence, text)) = parse_line(line) {
+                for len in 1..sequence.len() {
+                    table.prefixes.insert(sequence[..len].to_vec());
+                }
+                table.sequences.insert(sequence, text);
+            }
+        }
+        table
+    }
+
+    /// Read and parse a compose file, e.g. `~/.XCompose`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        std::fs::read_to_string(path).map(|source| Self::parse(&source))
+    }
+
+    /// Number of compose sequences within the table.
+    pub fn len(&self) -> usize {
+        self.sequences.len()
+    }
+
+    /// Return `true` if the table contains no compose sequences.
+    pub fn is_empty(&self) -> bool {
+        self.sequences.is_empty()
+    }
+
+    /// Append `keysym` to the `pending` sequence and look it up.
+    pub(crate) fn feed(&self, pending: &mut Vec<u32>, keysym: u32) -> ComposeResult {
+        // modifiers must not interrupt a sequence
+        if keysym == 0 || (0xffe1..=0xffee).contains(&keysym) || (0xfe01..=0xfe0f).contains(&keysym)
+        {
+            return if pending.is_empty() {
+                ComposeResult::Ignored
+            } else {
+                ComposeResult::Consumed
+            };
+        }
+        pending.push(keysym);
+        if let Some(text) = self.sequences.get(pending) {
+            pending.clear();
+            ComposeResult::Composed(text.clone())
+        } else if self.prefixes.contains(pending) {
+            ComposeResult::Consumed
+        } else if pending.len() > 1 {
+            // an unknown sequence is cancelled as a whole
+            pending.clear();
+            ComposeResult::Consumed
+        } else {
+            pending.clear();
+            ComposeResult::Ignored
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use xcb::x::Window;
+
+use crate::{ImeClient, Point, Rectangle};
+
+/// Keeps the IME window next to the caret of a text widget.
+///
+/// [`CursorTracker`] asks a closure for the caret rectangle whenever the application signals
+/// that the caret may have moved and passes its position on to [`ImeClient::update_pos`]. XIM
+/// expects the spot location at the baseline of the caret, so the bottom left corner of the
+/// rectangle is sent. Updates are only sent if the position actually changed.
+pub struct CursorTracker<F> {
+    win: Window,
+    caret: F,
+    last: Option<Point>,
+}
+
+impl<F, R> CursorTracker<F>
+where
+    F: FnMut() -> R,
+    R: Into<Rectangle>,
+{
+    /// Create a new [`CursorTracker`] for the window `win`.
+    ///
+    /// `caret` returns the caret rectangle relative to the top left corner of `win`, e.g. as
+    /// [`Rectangle`] or [`xcb::x::Rectangle`].
+    pub fn new(win: Window, caret: F) -> Self {
+        Self {
+            win,
+            caret,
+            last: None,
+        }
+    }
+
+    /// Window the caret belongs to.
+    pub fn window(&self) -> Window {
+        self.win
+    }
+
+    /// Query the caret rectangle and move the IME window if the caret moved.
+    ///
+    /// Return `true` if the position has been passed to [`ImeClient::update_pos`].
+    pub fn caret_moved(&mut self, ime: &mut ImeClient) -> bool {
+        let rect = (self.caret)().into();
+        let height = rect.height.min(i16::MAX as u16) as i16;
+        let spot = Point {
+            x: rect.x,
+            y: rect.y.saturating_add(height),
+        };
+        if self.last == Some(spot) {
+            return false;
+        }
+        self.last = Some(spot);
+        ime.update_pos(self.win, spot.x, spot.y);
+        true
+    }
+
+    /// Forget the last position, so the next call to [`caret_moved`] sends it again, e.g. after
+    /// the window regained focus.
+    ///
+    /// [`caret_moved`]: CursorTracker::caret_moved
+    pub fn invalidate(&mut self) {
+        self.last = None;
+    }
+}
+
+/// Position of the glyph cluster the caret follows, see [`ImeClient::update_pos_cluster`].
+///
+/// Coordinates are relative to the top left corner of the window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClusterSpot {
+    /// Left edge of the cluster.
+    pub baseline_x: i32,
+    /// Baseline of the line containing the cluster.
+    pub baseline_y: i32,
+    /// Width of the cluster.
+    pub cluster_width: i32,
+    /// Whether the cluster belongs to a right-to-left run, e.g. Arabic or Hebrew text.
+    pub rtl: bool,
+}
+
+impl ClusterSpot {
+    /// Spot location at which text typed after the cluster is inserted, clamped to the range of
+    /// X coordinates.
+    ///
+    /// This is the trailing edge of the cluster: its right edge for left-to-right text and its
+    /// left edge for right-to-left text.
+    pub fn spot(&self) -> Point {
+        let clamp = |v: i32| v.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        let x = if self.rtl {
+            self.baseline_x
+        } else {
+            self.baseline_x.saturating_add(self.cluster_width)
+        };
+        Point {
+            x: clamp(x),
+            y: clamp(self.baseline_y),
+        }
+    }
+}
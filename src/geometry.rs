@@ -0,0 +1,100 @@
+use crate::clib::{xcb_point_t, xcb_rectangle_t};
+
+/// Position within a window in pixels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Point {
+    pub x: i16,
+    pub y: i16,
+}
+
+/// Rectangle within a window in pixels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Rectangle {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl From<(i16, i16)> for Point {
+    fn from((x, y): (i16, i16)) -> Self {
+        Self { x, y }
+    }
+}
+
+impl From<Point> for (i16, i16) {
+    fn from(point: Point) -> Self {
+        (point.x, point.y)
+    }
+}
+
+impl From<xcb::x::Point> for Point {
+    fn from(point: xcb::x::Point) -> Self {
+        Self {
+            x: point.x,
+            y: point.y,
+        }
+    }
+}
+
+impl From<Point> for xcb::x::Point {
+    fn from(point: Point) -> Self {
+        Self {
+            x: point.x,
+            y: point.y,
+        }
+    }
+}
+
+impl From<Point> for xcb_point_t {
+    fn from(point: Point) -> Self {
+        Self {
+            x: point.x,
+            y: point.y,
+        }
+    }
+}
+
+impl From<xcb::x::Rectangle> for Rectangle {
+    fn from(rect: xcb::x::Rectangle) -> Self {
+        Self {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+impl From<Rectangle> for xcb::x::Rectangle {
+    fn from(rect: Rectangle) -> Self {
+        Self {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+impl From<xcb_rectangle_t> for Rectangle {
+    fn from(rect: xcb_rectangle_t) -> Self {
+        Self {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+impl From<Rectangle> for xcb_rectangle_t {
+    fn from(rect: Rectangle) -> Self {
+        Self {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
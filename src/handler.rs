@@ -0,0 +1,68 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use xcb::x::Window;
+
+use crate::ImeClient;
+#[cfg(feature = "preedit")]
+use crate::PreeditInfo;
+
+/// Handler for all events of the IME, see [`ImeClient::set_handler`].
+///
+/// Every method corresponds to one of the callbacks of [`ImeClient`] and does nothing by default,
+/// so only the events of interest have to be implemented. The window the event belongs to is
+/// supplied as first argument just like for the callbacks. The `PreeditCaret`, `StatusStart` and
+/// `StatusDone` requests of the IME server are not handled by this crate, so there are no methods
+/// for them.
+pub trait CompositionHandler {
+    /// Called when the IME starts drawing the preedit, see [`ImeClient::set_preedit_start_cb`].
+    #[cfg(feature = "preedit")]
+    fn on_preedit_start(&mut self, _win: Window) {}
+
+    /// Called when the preedit text changed, see [`ImeClient::set_preedit_draw_cb`].
+    #[cfg(feature = "preedit")]
+    fn on_preedit_draw(&mut self, _win: Window, _info: PreeditInfo<'_>) {}
+
+    /// Called when the IME stops drawing the preedit, see [`ImeClient::set_preedit_done_cb`].
+    #[cfg(feature = "preedit")]
+    fn on_preedit_done(&mut self, _win: Window) {}
+
+    /// Called when the status of the IME changed, see [`ImeClient::set_status_draw_cb`].
+    #[cfg(feature = "status")]
+    fn on_status_draw(&mut self, _win: Window, _text: &str) {}
+
+    /// Called when input composition is done, see [`ImeClient::set_commit_string_cb`].
+    fn on_commit(&mut self, _win: Window, _text: &str) {}
+
+    /// Called for key events unhandled by the IME, see [`ImeClient::set_forward_event_cb`].
+    #[cfg(feature = "forward")]
+    fn on_forward(&mut self, _win: Window, _event: &xcb::Event) {}
+}
+
+/// Set the callbacks of `ime` to call the methods of `handler`.
+pub(crate) fn register<H>(ime: &mut ImeClient, handler: H)
+where
+    H: CompositionHandler + 'static,
+{
+    let handler = Rc::new(RefCell::new(handler));
+    #[cfg(feature = "preedit")]
+    {
+        let h = handler.clone();
+        ime.set_preedit_start_cb(move |win| h.borrow_mut().on_preedit_start(win));
+        let h = handler.clone();
+        ime.set_preedit_draw_cb(move |win, info| h.borrow_mut().on_preedit_draw(win, info));
+        let h = handler.clone();
+        ime.set_preedit_done_cb(move |win| h.borrow_mut().on_preedit_done(win));
+    }
+    #[cfg(feature = "status")]
+    {
+        let h = handler.clone();
+        ime.set_status_draw_cb(move |win, text| h.borrow_mut().on_status_draw(win, text));
+    }
+    #[cfg(feature = "forward")]
+    {
+        let h = handler.clone();
+        ime.set_forward_event_cb(move |win, event| h.borrow_mut().on_forward(win, event));
+    }
+    ime.set_commit_string_cb(move |win, text| handler.borrow_mut().on_commit(win, text));
+}
@@ -0,0 +1,131 @@
+use xcb::x::KeyButMask;
+
+/// Mapping of keycodes to keysyms as reported by the X server.
+pub(crate) struct KeySymbols {
+    min_keycode: u8,
+    keysyms_per_keycode: u8,
+    keysyms: Vec<u32>,
+}
+
+impl KeySymbols {
+    /// Query the keyboard mapping of the X server.
+    pub(crate) fn query(conn: &xcb::Connection) -> Option<Self> {
+        let setup = conn.get_setup();
+        let min_keycode = setup.min_keycode();
+        let max_keycode = setup.max_keycode();
+        let cookie = conn.send_request(&xcb::x::GetKeyboardMapping {
+            first_keycode: min_keycode,
+            count: max_keycode - min_keycode + 1,
+        });
+        let reply = conn.wait_for_reply(cookie).ok()?;
+        Some(Self {
+            min_keycode,
+            keysyms_per_keycode: reply.keysyms_per_keycode(),
+            keysyms: reply.keysyms().to_vec(),
+        })
+    }
+
+    /// Resolve the keysym of `keycode` given the modifier `state` of a key event.
+    ///
+    /// Return `0` (`NoSymbol`) if the keycode has no keysym.
+    pub(crate) fn keysym(&self, keycode: u8, state: u16) -> u32 {
+        let per_keycode = self.keysyms_per_keycode as usize;
+        if keycode < self.min_keycode || per_keycode == 0 {
+            return 0;
+        }
+        let start = (keycode - self.min_keycode) as usize * per_keycode;
+        let syms = match self.keysyms.get(start..start + per_keycode) {
+            Some(syms) => syms,
+            None => return 0,
+        };
+        let shifted = state as u32 & KeyButMask::SHIFT.bits() != 0;
+        match (shifted, syms.get(1)) {
+            (true, Some(&sym)) if sym != 0 => sym,
+            _ => syms[0],
+        }
+    }
+}
+
+/// Map `text` to the keysyms of key events typing it.
+///
+/// Return the keysym of every character together with whether `Shift` has to be held down as on a
+/// US keyboard layout, and the characters that could not be mapped. Printable ASCII and Latin-1
+/// characters map to the keysyms equal to their code, newline and tab to `Return` and `Tab`.
+/// `Shift` is only held down for uppercase letters and the shifted symbols of the US layout, so
+/// e.g. `ß`, `×` and `÷` are typed without it. Other characters, e.g. CJK text, are left out of
+/// the keysyms and returned as the second value, in order.
+///
+/// This is meant for passing committed text on to consumers that only understand key events,
+/// see [`ImeClient::set_commit_string_cb`](crate::ImeClient::set_commit_string_cb).
+pub fn string_to_key_events(text: &str) -> (Vec<(u32, bool)>, String) {
+    let mut keys = Vec::with_capacity(text.len());
+    let mut unmapped = String::new();
+    for c in text.chars() {
+        let code = c as u32;
+        let key = match c {
+            '\n' => (0xff0d, false),
+            '\t' => (0xff09, false),
+            ' '..='~' => (
+                code,
+                c.is_ascii_uppercase() || "~!@#$%^&*()_+{}|:\"<>?".contains(c),
+            ),
+            // only letters with a lowercase counterpart are uppercase, unlike `ß` or `×`
+            '\u{a0}'..='\u{ff}' => (code, c.is_uppercase()),
+            _ => {
+                unmapped.push(c);
+                continue;
+            }
+        };
+        keys.push(key);
+    }
+    (keys, unmapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_shift() {
+        let (keys, unmapped) = string_to_key_events("aA1!\n\t");
+        assert_eq!(
+            keys,
+            [
+                (0x61, false),
+                (0x41, true),
+                (0x31, false),
+                (0x21, true),
+                (0xff0d, false),
+                (0xff09, false),
+            ]
+        );
+        assert!(unmapped.is_empty());
+    }
+
+    #[test]
+    fn latin1_shift() {
+        let (keys, unmapped) =
+            string_to_key_events("\u{e4}\u{c4}\u{df}\u{d7}\u{f7}\u{de}\u{fe}\u{ff}");
+        assert_eq!(
+            keys,
+            [
+                (0xe4, false),
+                (0xc4, true),
+                (0xdf, false),
+                (0xd7, false),
+                (0xf7, false),
+                (0xde, true),
+                (0xfe, false),
+                (0xff, false),
+            ]
+        );
+        assert!(unmapped.is_empty());
+    }
+
+    #[test]
+    fn unmapped_characters() {
+        let (keys, unmapped) = string_to_key_events("a\u{4f60}b\u{597d}");
+        assert_eq!(keys, [(0x61, false), (0x62, false)]);
+        assert_eq!(unmapped, "\u{4f60}\u{597d}");
+    }
+}
@@ -7,22 +7,81 @@ Input Method Protocol](https://www.x.org/releases/current/doc/libX11/XIM/xim.htm
 of said library as simply as possible.
 
 To get started quickly, consult the examples folder.
+
+# Limitations
+
+Some parts of the XIM protocol are not implemented by the client side of xcb-imdkit and can
+therefore not be offered by this crate:
+
+- String conversion (`XIM_STR_CONVERSION`), which XIM uses to retrieve or delete the text
+  surrounding the cursor. Neither the request from the server nor a way to proactively push
+  surrounding text is exposed, so reconversion based on surrounding text is not available. For
+  the same reason the IME server cannot be told about caret movements within the application's
+  text; the spot location set with [`ImeClient::update_pos`] is the only caret information sent.
+- Querying the preedit text. XIM has no request for the text being composed, it is only sent to
+  clients using [`InputStyle::PREEDIT_CALLBACKS`]. With other styles, e.g. over-the-spot, the IME
+  server draws the preedit itself and the preedit callbacks are never called.
+- Transports other than X. XIM messages are always exchanged through `ClientMessage` events and
+  window properties on the X connection, with the communication window described at
+  [`ImeClient::comm_window`]; the TCP and local socket transports of XIM cannot be selected and
+  there is no transport negotiation to inspect. An IME that works outside a sandbox but not inside
+  is therefore usually not found at all, e.g. because `XMODIFIERS` is not passed into the sandbox
+  or the IME server runs on a different X display.
+
+# Features
+
+The callbacks besides the commit callback can be left out of the build by disabling the default
+features `preedit`, `status` and `forward`. No `surrounding` feature is offered, see above. The
+`compose` feature enables client-side composition, see `ComposeTable`.
+
+# Toolkit integration
+
+[`ImeClient`] needs the [`xcb::Connection`] the application's windows were created with and every
+event received on it, see [`ImeClient::process_event`]. Toolkits that own the X connection have
+to provide both. winit does not: it neither exposes the raw X11 events it receives nor an XCB
+connection and instead talks XIM itself, hence no winit integration is offered. Use winit's own
+IME support (`Window::set_ime_allowed` and `WindowEvent::Ime`) there.
 */
 
 #[macro_use]
 extern crate lazy_static;
 
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
 use std::os::raw::{c_char, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 use xcb::x::Window;
-use xcb::{Raw, Xid, XidNew};
+use xcb::{Xid, XidNew};
 
 use bitflags::bitflags;
 
 use clib::*;
+use keysyms::KeySymbols;
 
 mod clib;
+#[cfg(feature = "compose")]
+mod compose;
+mod cursor_tracker;
+mod geometry;
+mod handler;
+mod keysyms;
+mod registry;
+mod text_input;
+
+#[cfg(feature = "compose")]
+pub use compose::ComposeTable;
+pub use cursor_tracker::{ClusterSpot, CursorTracker};
+pub use geometry::{Point, Rectangle};
+pub use handler::CompositionHandler;
+pub use keysyms::string_to_key_events;
+pub use registry::ImeRegistry;
+pub use text_input::TextInput;
 
 type LogFn = dyn for<'a> FnMut(&'a str) + Send;
 
@@ -34,89 +93,305 @@ extern "C" {
     fn xcb_log_wrapper(msg: *const c_char, ...);
 }
 
+/// Receive a log message from `xcb_log_wrapper` in logging.c.
+///
+/// `xcb_log_wrapper` formats the message including all of its arguments into a buffer before
+/// calling this function, so `msg` is always a single, NUL-terminated string that is only valid
+/// for the duration of the call.
 #[no_mangle]
-fn rust_log(msg: *const c_char) {
+extern "C" fn rust_log(msg: *const c_char) {
+    if msg.is_null() {
+        return;
+    }
     let msg = unsafe { std::ffi::CStr::from_ptr(msg) }.to_string_lossy();
-    let msg = msg.trim();
-    if let Some(logger) = LOGGER.lock().unwrap().as_mut() {
-        logger(msg);
+    log(msg.trim());
+}
+
+/// Lock the logger, ignoring that a previous user of the lock panicked.
+fn lock_logger() -> MutexGuard<'static, Option<Box<LogFn>>> {
+    LOGGER.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Pass `msg` to the logger set with [`ImeClient::set_logger`].
+///
+/// A panic of the logger is caught and the message dropped, as it would otherwise unwind into
+/// xcb-imdkit.
+fn log(msg: &str) {
+    if let Some(logger) = lock_logger().as_mut() {
+        let _ = catch_unwind(AssertUnwindSafe(|| logger(msg)));
     }
 }
 
-extern "C" fn create_ic_callback(im: *mut xcb_xim_t, new_ic: xcb_xic_t, user_data: *mut c_void) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    ime.ic = Some(new_ic);
-    unsafe {
-        xcb_xim_set_ic_focus(im, new_ic);
+/// Run the body of a callback called by xcb-imdkit.
+///
+/// Unwinding into C is undefined behavior, so a panic, e.g. of a callback set by the user, is
+/// caught and logged. The panic is not propagated and the event that caused it is lost.
+fn ffi_guard<F: FnOnce()>(f: F) {
+    if catch_unwind(AssertUnwindSafe(f)).is_err() {
+        log("caught panic within a callback, the event is dropped");
     }
 }
 
+extern "C" fn get_ic_style_callback(
+    _im: *mut xcb_xim_t,
+    _ic: xcb_xic_t,
+    reply: *mut xcb_im_get_ic_values_reply_fr_t,
+    user_data: *mut c_void,
+) {
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        let attrs = unsafe {
+            let reply = &*reply;
+            from_raw_parts(reply.ic_attribute.items, reply.ic_attribute.size as usize)
+        };
+        let style = attrs.first().and_then(|attr| {
+            let value = unsafe { from_raw_parts(attr.value, attr.value_length as usize) };
+            read_card32(value)
+        });
+        if let (Some(style), Some(_)) = (style, ime.ic) {
+            ime.active_style = Some(InputStyle::from_bits_truncate(style));
+        }
+    });
+}
+
+#[cfg(feature = "preedit")]
+extern "C" fn get_visible_position_callback(
+    _im: *mut xcb_xim_t,
+    _ic: xcb_xic_t,
+    reply: *mut xcb_im_get_ic_values_reply_fr_t,
+    user_data: *mut c_void,
+) {
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        let attrs = unsafe {
+            let reply = &*reply;
+            from_raw_parts(reply.ic_attribute.items, reply.ic_attribute.size as usize)
+        };
+        ime.visible_position_reply = attrs.first().and_then(|attr| {
+            let value = unsafe { from_raw_parts(attr.value, attr.value_length as usize) };
+            read_card32(value)
+        });
+        ime.visible_position_pending = false;
+    });
+}
+
+extern "C" fn reset_ic_callback(
+    im: *mut xcb_xim_t,
+    _ic: xcb_xic_t,
+    reply: *mut xcb_im_reset_ic_reply_fr_t,
+    user_data: *mut c_void,
+) {
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        let reply = unsafe { &*reply };
+        let encoding = ime.commit_encoding(im);
+        let text = unsafe {
+            decode_xim_string(
+                encoding,
+                reply.committed_string as _,
+                reply.byte_length_of_committed_string as usize,
+            )
+        };
+        ime.reset_reply = Some(text);
+        ime.reset_pending = false;
+    });
+}
+
+extern "C" fn get_spot_callback(
+    _im: *mut xcb_xim_t,
+    _ic: xcb_xic_t,
+    reply: *mut xcb_im_get_ic_values_reply_fr_t,
+    user_data: *mut c_void,
+) {
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        let attrs = unsafe {
+            let reply = &*reply;
+            from_raw_parts(reply.ic_attribute.items, reply.ic_attribute.size as usize)
+        };
+        // the preedit attributes are a nested list of CARD16 id, CARD16 length, value and padding;
+        // only the spot location has been requested, so it is the first value of an XPoint's size
+        let spot = attrs.first().and_then(|attr| {
+            let mut value = unsafe { from_raw_parts(attr.value, attr.value_length as usize) };
+            while value.len() >= 4 {
+                let length = u16::from_ne_bytes([value[2], value[3]]) as usize;
+                let entry = value.get(4..4 + length)?;
+                if length == 4 {
+                    let x = i16::from_ne_bytes([entry[0], entry[1]]);
+                    let y = i16::from_ne_bytes([entry[2], entry[3]]);
+                    return Some((x, y));
+                }
+                value = value.get(4 + ((length + 3) & !3)..)?;
+            }
+            None
+        });
+        ime.spot_readback = spot;
+        ime.spot_readback_pending = false;
+    });
+}
+
+extern "C" fn create_ic_callback(im: *mut xcb_xim_t, new_ic: xcb_xic_t, user_data: *mut c_void) {
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        ime.ic = Some(new_ic);
+        ime.ic_pending = false;
+        ime.ic_requested_at = None;
+        ime.ic_focused = ime.focus_req && !ime.grab_suspended;
+        // assume the requested style was granted until the IME server tells otherwise
+        ime.active_style = Some(ime.ic_style);
+        unsafe {
+            xcb_xim_get_ic_values(
+                im,
+                new_ic,
+                Some(get_ic_style_callback),
+                user_data,
+                XCB_XIM_XNInputStyle,
+                std::ptr::null_mut::<c_void>(),
+            );
+        }
+        ime.forward_event_mask = DEFAULT_FORWARD_EVENT_MASK;
+        if ime.ic_focused {
+            unsafe {
+                xcb_xim_set_ic_focus(im, new_ic);
+            }
+        }
+        if let Some(mut key) = ime.replay_key.take() {
+            unsafe {
+                xcb_xim_forward_event(im, new_ic, &mut key);
+            }
+            ime.stats.forwarded += 1;
+            ime.unanswered_since.get_or_insert_with(Instant::now);
+        }
+    });
+}
+
+extern "C" fn get_input_styles_callback(
+    _im: *mut xcb_xim_t,
+    reply: *mut xcb_im_get_im_values_reply_fr_t,
+    user_data: *mut c_void,
+) {
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        let attrs = unsafe {
+            let reply = &*reply;
+            from_raw_parts(
+                reply.im_attribute_returned.items,
+                reply.im_attribute_returned.size as usize,
+            )
+        };
+        // XIMStyles: CARD16 number of styles, CARD16 unused, followed by the CARD32 styles
+        let styles = attrs.first().and_then(|attr| {
+            let value = unsafe { from_raw_parts(attr.value, attr.value_length as usize) };
+            let count = u16::from_ne_bytes([*value.first()?, *value.get(1)?]) as usize;
+            let styles = value.get(4..)?.chunks_exact(4).take(count);
+            styles
+                .map(|style| read_card32(style).map(InputStyle::from_bits_truncate))
+                .collect()
+        });
+        ime.supported_styles = styles;
+        // the input context is created once the style can be chosen from the supported styles
+        if ime.state == ImeState::Open && ime.pos_req.win != 0 {
+            ime.try_open_ic();
+        }
+    });
+}
+
 extern "C" fn open_callback(im: *mut xcb_xim_t, user_data: *mut c_void) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let input_style = ime.input_style.bits();
-    let spot = xcb_point_t {
-        x: ime.pos_req.x,
-        y: ime.pos_req.y,
-    };
-    let w = &mut ime.pos_req.win as *mut u32;
-    unsafe {
-        let nested = xcb_xim_create_nested_list(
-            im,
-            XCB_XIM_XNSpotLocation,
-            &spot,
-            std::ptr::null_mut::<c_void>(),
-        );
-        xcb_xim_create_ic(
-            im,
-            Some(create_ic_callback),
-            user_data,
-            XCB_XIM_XNInputStyle,
-            &input_style,
-            XCB_XIM_XNClientWindow,
-            w,
-            XCB_XIM_XNFocusWindow,
-            w,
-            XCB_XIM_XNPreeditAttributes,
-            &nested,
-            std::ptr::null_mut::<c_void>(),
-        );
-        free(nested.data as _);
-    }
-    ime.pos_cur = ime.pos_req;
+    ffi_guard(|| {
+        let styles_queried = unsafe {
+            xcb_xim_get_im_values(
+                im,
+                Some(get_input_styles_callback),
+                user_data,
+                XCB_XIM_XNQueryInputStyle,
+                std::ptr::null_mut::<c_void>(),
+            )
+        };
+        let ime = unsafe { ime_from_user_data(user_data) };
+        ime.stats.opens += 1;
+        ime.set_state(ImeState::Open);
+        // without a window set by `update_pos` there is nothing to create an input context for yet,
+        // otherwise wait for the supported styles unless they cannot be queried
+        if ime.pos_req.win != 0 && !styles_queried {
+            ime.create_ic();
+        }
+    });
 }
 
+#[cfg(any(feature = "preedit", feature = "status"))]
 unsafe fn xim_encoding_to_utf8(
     im: *mut xcb_xim_t,
     xim_str: *const c_char,
     length: usize,
+) -> String {
+    decode_xim_string(
+        Encoding::from_raw(xcb_xim_get_encoding(im)),
+        xim_str,
+        length,
+    )
+}
+
+unsafe fn decode_xim_string(
+    encoding: Option<Encoding>,
+    xim_str: *const c_char,
+    length: usize,
 ) -> String {
     let mut buf: Vec<u8> = vec![];
-    if xcb_xim_get_encoding(im) == _xcb_xim_encoding_t_XCB_XIM_UTF8_STRING {
-        buf.extend(from_raw_parts(
-            xim_str as *const u8,
-            length as usize,
-        ));
-    } else if xcb_xim_get_encoding(im) == _xcb_xim_encoding_t_XCB_XIM_COMPOUND_TEXT {
-        let mut new_length = 0usize;
-        let utf8 = xcb_compound_text_to_utf8(xim_str, length as usize, &mut new_length);
-        if !utf8.is_null() {
-            buf.extend(from_raw_parts(utf8 as _, new_length));
-            free(utf8 as _);
+    decode_xim_bytes(encoding, xim_str, length, &mut buf);
+    // the server is not trusted to send valid UTF-8
+    String::from_utf8(buf)
+        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+}
+
+/// Convert a string received from the IME server to UTF-8 bytes, replacing the contents of `buf`.
+unsafe fn decode_xim_bytes(
+    encoding: Option<Encoding>,
+    xim_str: *const c_char,
+    length: usize,
+    buf: &mut Vec<u8>,
+) {
+    let bytes = if xim_str.is_null() {
+        &[]
+    } else {
+        from_raw_parts(xim_str as *const u8, length)
+    };
+    decode_xim_slice(encoding, bytes, buf);
+}
+
+/// Convert the bytes of a string received from the IME server to UTF-8, replacing the contents
+/// of `buf`.
+///
+/// Unlike `decode_xim_bytes` this is safe for arbitrary input, which allows to fuzz it, see
+/// `Encoding::decode`.
+fn decode_xim_slice(encoding: Option<Encoding>, bytes: &[u8], buf: &mut Vec<u8>) {
+    buf.clear();
+    match encoding {
+        Some(Encoding::Utf8String) => {
+            buf.extend_from_slice(bytes);
         }
+        Some(Encoding::CompoundText) => unsafe {
+            let mut new_length = 0usize;
+            let utf8 = xcb_compound_text_to_utf8(bytes.as_ptr() as _, bytes.len(), &mut new_length);
+            // `new_length` excludes the terminating NUL, which is not relied upon: exactly
+            // `new_length` bytes are copied, so no stray byte ends up in the string
+            if !utf8.is_null() {
+                buf.extend(from_raw_parts(utf8 as _, new_length));
+                free(utf8 as _);
+            }
+        },
+        None => {}
     }
-    String::from_utf8_unchecked(buf)
 }
 
 unsafe fn ime_from_user_data(user_data: *mut c_void) -> &'static mut ImeClient {
     &mut *(user_data as *mut ImeClient)
 }
 
-extern "C" fn disconnected_callback(
-    _im: *mut xcb_xim_t,
-    user_data: *mut c_void,
-) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    ime.ic.take();
+extern "C" fn disconnected_callback(_im: *mut xcb_xim_t, user_data: *mut c_void) {
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        ime.handle_disconnect();
+    });
 }
 
 extern "C" fn commit_string_callback(
@@ -129,79 +404,237 @@ extern "C" fn commit_string_callback(
     _n_keysym: usize,
     user_data: *mut c_void,
 ) {
-    let input = unsafe { xim_encoding_to_utf8(im, input, length as usize) };
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let win = unsafe { Window::new(ime.pos_req.win) };
-    ime.callbacks.commit_string.as_mut().map(|f| f(win, &input));
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        let encoding = ime.commit_encoding(im);
+        if let Some(f) = ime.callbacks.commit_raw.as_mut() {
+            let win = Window::new(ime.pos_req.win);
+            let bytes = unsafe { from_raw_parts(input as *const u8, length as usize) };
+            f(win, encoding, bytes);
+        }
+        // reuse the allocation of the previous commit
+        let mut buf = std::mem::take(&mut ime.commit_buffer);
+        unsafe { decode_xim_bytes(encoding, input, length as usize, &mut buf) };
+        let win = Window::new(ime.pos_req.win);
+        // the server is not trusted to send valid UTF-8
+        let text = String::from_utf8_lossy(&buf);
+        let text = ime.control_char_policy.apply(&text);
+        ime.deliver_commit(win, &text);
+        ime.commit_buffer = buf;
+    });
 }
 
 extern "C" fn update_pos_callback(_im: *mut xcb_xim_t, ic: xcb_xic_t, user_data: *mut c_void) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    if ime.pos_update_queued {
-        ime.pos_update_queued = false;
-        ime.send_pos_update(ic);
-    } else {
-        ime.is_processing_pos_update = false;
-    }
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        if ime.pos_update_queued {
+            ime.pos_update_queued = false;
+            ime.send_pos_update(ic);
+            // a focus change requested along with the window change follows it
+            ime.sync_focus();
+        } else {
+            ime.is_processing_pos_update = false;
+        }
+    });
+}
+
+extern "C" fn set_event_mask_callback(
+    _im: *mut xcb_xim_t,
+    _ic: xcb_xic_t,
+    forward_event_mask: u32,
+    _synchronous_event_mask: u32,
+    user_data: *mut c_void,
+) {
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        ime.forward_event_mask = forward_event_mask;
+    });
 }
 
+/// Events forwarded to the IME until the server requests a different set via
+/// `XIM_SET_EVENT_MASK`.
+const DEFAULT_FORWARD_EVENT_MASK: u32 =
+    xcb::x::EventMask::KEY_PRESS.bits() | xcb::x::EventMask::KEY_RELEASE.bits();
+
+/// Modifiers considered when matching passthrough keys, Caps Lock and Num Lock (Mod2) are
+/// ignored.
+const PASSTHROUGH_MODIFIER_MASK: u16 = (xcb::x::KeyButMask::SHIFT.bits()
+    | xcb::x::KeyButMask::CONTROL.bits()
+    | xcb::x::KeyButMask::MOD1.bits()
+    | xcb::x::KeyButMask::MOD3.bits()
+    | xcb::x::KeyButMask::MOD4.bits()
+    | xcb::x::KeyButMask::MOD5.bits()) as u16;
+
 const XCB_KEY_PRESS: u8 = 2;
 const XCB_KEY_RELEASE: u8 = 3;
 
+/// Name of the `XNVisiblePosition` attribute, which xcb-imdkit has no constant for.
+#[cfg(feature = "preedit")]
+const XN_VISIBLE_POSITION: &[u8] = b"visiblePosition\0";
+
+/// Return whether `event` has been sent with `SendEvent` by another client.
+fn is_synthetic(event: &xcb::Event) -> bool {
+    unsafe { (*event.as_raw()).response_type & 0x80 != 0 }
+}
+
+/// Copy a key event owned by xcb-imdkit into an [`xcb::Event`] that owns its memory.
+///
+/// Return `None` if `event` is null or not a key event, which only a misbehaving IME server can
+/// cause.
+#[cfg(feature = "forward")]
+unsafe fn key_event_from_ptr(event: *const xcb_key_press_event_t) -> Option<xcb::Event> {
+    use xcb::Raw;
+
+    if event.is_null() {
+        return None;
+    }
+    let pressed = match (*event).response_type & 0x7f {
+        XCB_KEY_PRESS => true,
+        XCB_KEY_RELEASE => false,
+        _ => return None,
+    };
+    let size = std::mem::size_of::<xcb::ffi::xcb_generic_event_t>()
+        .max(std::mem::size_of::<xcb_key_press_event_t>());
+    let ptr = malloc(size) as *mut xcb::ffi::xcb_generic_event_t;
+    std::ptr::copy_nonoverlapping(
+        event as *const u8,
+        ptr as *mut u8,
+        std::mem::size_of::<xcb_key_press_event_t>(),
+    );
+    Some(if pressed {
+        xcb::Event::X(xcb::x::Event::KeyPress(xcb::x::KeyPressEvent::from_raw(
+            ptr,
+        )))
+    } else {
+        xcb::Event::X(xcb::x::Event::KeyRelease(
+            xcb::x::KeyReleaseEvent::from_raw(ptr),
+        ))
+    })
+}
+
+#[cfg(feature = "forward")]
 extern "C" fn forward_event_callback(
     _im: *mut xcb_xim_t,
     _ic: xcb_xic_t,
     event: *mut xcb_key_press_event_t,
     user_data: *mut c_void,
 ) {
-    let pressed = unsafe { ((*event).response_type & 0x7f) == XCB_KEY_PRESS };
-    let ptr = event as *const xcb::ffi::xcb_generic_event_t;
-    let event = unsafe {
-        if pressed {
-            xcb::Event::X(xcb::x::Event::KeyPress(xcb::x::KeyPressEvent::from_raw(
-                ptr as _,
-            )))
-        } else {
-            xcb::Event::X(xcb::x::Event::KeyRelease(
-                xcb::x::KeyReleaseEvent::from_raw(ptr as _),
-            ))
-        }
-    };
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let win = unsafe { Window::new(ime.pos_req.win) };
-    ime.callbacks.forward_event.as_mut().map(|f| f(win, &event));
-
-    // xcb::KeyPressEvent has a Drop impl that will free `event`, but since we don't own it, we
-    // have to prevent that from happening
-    std::mem::forget(event);
+    ffi_guard(|| {
+        let event = match unsafe { key_event_from_ptr(event) } {
+            Some(event) => event,
+            None => {
+                log("dropping invalid key event forwarded by the IME server");
+                return;
+            }
+        };
+        let ime = unsafe { ime_from_user_data(user_data) };
+        ime.stats.forwarded_back += 1;
+        ime.deliver_forwarded(event);
+    });
 }
 
+#[cfg(feature = "preedit")]
 extern "C" fn preedit_start_callback(_im: *mut xcb_xim_t, _ic: xcb_xic_t, user_data: *mut c_void) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let win = unsafe { Window::new(ime.pos_req.win) };
-    ime.callbacks.preedit_start.as_mut().map(|f| f(win));
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        ime.preedit = PreeditString::default();
+        let win = Window::new(ime.pos_req.win);
+        if let Some(events) = ime.events.as_mut() {
+            events.push(ImeEvent::PreeditStart { win });
+            return;
+        }
+        if let Some(f) = ime.callbacks.preedit_start.as_mut() {
+            f(win);
+        }
+        if let Some(f) = ime.callbacks.preedit_phase.as_mut() {
+            f(win, PreeditPhase::Start);
+        }
+    });
 }
 
+#[cfg(feature = "preedit")]
 extern "C" fn preedit_draw_callback(
     im: *mut xcb_xim_t,
     _ic: xcb_xic_t,
     frame: *mut xcb_im_preedit_draw_fr_t,
     user_data: *mut c_void,
 ) {
-    let frame = unsafe { &*frame };
-    let preedit_info = PreeditInfo { inner: frame, im };
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let win = unsafe { Window::new(ime.pos_req.win) };
-    ime.callbacks
-        .preedit_draw
-        .as_mut()
-        .map(|f| f(win, preedit_info));
+    ffi_guard(|| {
+        let frame = unsafe { &*frame };
+        let ime = unsafe { ime_from_user_data(user_data) };
+        let max_bytes = ime.max_preedit_bytes;
+        // decoded once, the callbacks share the result
+        let change = unsafe { PreeditString::from_draw(im, frame, max_bytes) };
+        ime.preedit.apply_draw(&change, max_bytes);
+        let win = Window::new(ime.pos_req.win);
+        if let Some(events) = ime.events.as_mut() {
+            events.push(ImeEvent::PreeditChanged {
+                win,
+                preedit: change,
+            });
+            return;
+        }
+        if let Some(f) = ime.callbacks.composition.as_mut() {
+            let update = CompositionUpdate {
+                committed: ime.pending_composition_commit.take().map(|(_, text)| text),
+                preedit: Some(change.clone()),
+            };
+            f(win, update);
+        }
+        if let Some(f) = ime.callbacks.preedit_draw.as_mut() {
+            let preedit_info = PreeditInfo {
+                draw: &change,
+                current: &ime.preedit,
+            };
+            f(win, preedit_info);
+        }
+    });
 }
 
+#[cfg(feature = "preedit")]
 extern "C" fn preedit_done_callback(_im: *mut xcb_xim_t, _ic: xcb_xic_t, user_data: *mut c_void) {
-    let ime = unsafe { ime_from_user_data(user_data) };
-    let win = unsafe { Window::new(ime.pos_req.win) };
-    ime.callbacks.preedit_done.as_mut().map(|f| f(win));
+    ffi_guard(|| {
+        let ime = unsafe { ime_from_user_data(user_data) };
+        ime.preedit = PreeditString::default();
+        let win = Window::new(ime.pos_req.win);
+        if let Some(events) = ime.events.as_mut() {
+            events.push(ImeEvent::PreeditDone { win });
+            return;
+        }
+        if let Some(f) = ime.callbacks.preedit_done.as_mut() {
+            f(win);
+        }
+        if let Some(f) = ime.callbacks.preedit_phase.as_mut() {
+            f(win, PreeditPhase::Stop);
+        }
+    });
+}
+
+#[cfg(feature = "status")]
+extern "C" fn status_draw_text_callback(
+    im: *mut xcb_xim_t,
+    _ic: xcb_xic_t,
+    frame: *mut xcb_im_status_draw_text_fr_t,
+    user_data: *mut c_void,
+) {
+    ffi_guard(|| {
+        let frame = unsafe { &*frame };
+        let text = unsafe {
+            xim_encoding_to_utf8(
+                im,
+                frame.status_string as _,
+                frame.length_of_status_string as usize,
+            )
+        };
+        let ime = unsafe { ime_from_user_data(user_data) };
+        let win = Window::new(ime.pos_req.win);
+        ime.status_text = Some((win, text.clone()));
+        if let Some(events) = ime.events.as_mut() {
+            events.push(ImeEvent::StatusChanged { win, text });
+        } else if let Some(f) = ime.callbacks.status_draw.as_mut() {
+            f(win, &text);
+        }
+    });
 }
 
 bitflags! {
@@ -216,6 +649,77 @@ bitflags! {
         /// inside the application and not only within the IME. The IME may stop displaying its
         /// cursor if this flag is set.
         const PREEDIT_CALLBACKS = _xcb_im_style_t_XCB_IM_PreeditCallbacks;
+
+        /// The IME draws the preedit text into an area supplied by the application.
+        const PREEDIT_AREA = _xcb_im_style_t_XCB_IM_PreeditArea;
+
+        /// The IME draws the preedit text in its own window at the position set with
+        /// [`ImeClient::update_pos`] (over-the-spot).
+        const PREEDIT_POSITION = _xcb_im_style_t_XCB_IM_PreeditPosition;
+
+        /// The IME draws the preedit text in its own window, independently of the application
+        /// (root-window).
+        const PREEDIT_NOTHING = _xcb_im_style_t_XCB_IM_PreeditNothing;
+
+        /// The preedit text is not displayed at all.
+        const PREEDIT_NONE = _xcb_im_style_t_XCB_IM_PreeditNone;
+
+        /// The IME draws its status into an area supplied by the application.
+        const STATUS_AREA = _xcb_im_style_t_XCB_IM_StatusArea;
+
+        /// Enable calling of the status callback set with [`ImeClient::set_status_draw_cb`]. This
+        /// enables displaying the status of the IME, e.g. the current input mode, inside the
+        /// application.
+        const STATUS_CALLBACKS = _xcb_im_style_t_XCB_IM_StatusCallbacks;
+
+        /// The IME displays its status independently of the application.
+        const STATUS_NOTHING = _xcb_im_style_t_XCB_IM_StatusNothing;
+
+        /// The status is not displayed at all.
+        const STATUS_NONE = _xcb_im_style_t_XCB_IM_StatusNone;
+    }
+}
+
+impl InputStyle {
+    /// Create an [`InputStyle`] from the raw `_xcb_im_style_t_*` bitmask used by xcb-imdkit.
+    ///
+    /// Unknown bits are dropped.
+    pub fn from_raw(raw: u32) -> Self {
+        Self::from_bits_truncate(raw)
+    }
+
+    /// Raw `_xcb_im_style_t_*` bitmask used by xcb-imdkit.
+    pub fn to_raw(self) -> u32 {
+        self.bits()
+    }
+}
+
+impl std::fmt::Display for InputStyle {
+    /// Format the style as the names of its flags joined by `|`, e.g.
+    /// `PREEDIT_POSITION | STATUS_NOTHING`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const NAMES: &[(InputStyle, &str)] = &[
+            (InputStyle::PREEDIT_CALLBACKS, "PREEDIT_CALLBACKS"),
+            (InputStyle::PREEDIT_AREA, "PREEDIT_AREA"),
+            (InputStyle::PREEDIT_POSITION, "PREEDIT_POSITION"),
+            (InputStyle::PREEDIT_NOTHING, "PREEDIT_NOTHING"),
+            (InputStyle::PREEDIT_NONE, "PREEDIT_NONE"),
+            (InputStyle::STATUS_AREA, "STATUS_AREA"),
+            (InputStyle::STATUS_CALLBACKS, "STATUS_CALLBACKS"),
+            (InputStyle::STATUS_NOTHING, "STATUS_NOTHING"),
+            (InputStyle::STATUS_NONE, "STATUS_NONE"),
+        ];
+        if self.is_empty() {
+            return f.write_str("DEFAULT");
+        }
+        let mut names = NAMES.iter().filter(|(flag, _)| self.contains(*flag));
+        if let Some((_, name)) = names.next() {
+            f.write_str(name)?;
+        }
+        for (_, name) in names {
+            write!(f, " | {}", name)?;
+        }
+        Ok(())
     }
 }
 
@@ -255,18 +759,365 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Modifier keys held down during a key event, see [`ForwardedKey`].
+    pub struct Modifiers: u16 {
+        const SHIFT = xcb::x::KeyButMask::SHIFT.bits() as u16;
+        const CONTROL = xcb::x::KeyButMask::CONTROL.bits() as u16;
+        /// Usually `Alt`, i.e. `Mod1`.
+        const ALT = xcb::x::KeyButMask::MOD1.bits() as u16;
+        /// Usually `Super`, i.e. `Mod4`.
+        const SUPER = xcb::x::KeyButMask::MOD4.bits() as u16;
+    }
+}
+
+/// Key event unhandled by the IME, see [`ImeClient::set_forward_key_cb`].
+#[cfg(feature = "forward")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForwardedKey {
+    /// Keysym of the key, resolved including the Shift level.
+    pub keysym: u32,
+    /// Keycode of the key.
+    pub keycode: u8,
+    pub modifiers: Modifiers,
+    /// `true` for key press and `false` for key release events.
+    pub pressed: bool,
+    pub window: Window,
+    /// Time of the event in milliseconds.
+    pub time: u32,
+    /// Token of the key event, see [`KeyToken`].
+    pub token: KeyToken,
+    /// `true` if the event is marked as sent by another client with `SendEvent`, see
+    /// [`ImeClient::set_ignore_synthetic_keys`].
+    pub synthetic: bool,
+}
+
+/// Identifies a key event across the round trip through the IME server.
+///
+/// Key events forwarded to the IME server by [`ImeClient::process_event`] and sent back unhandled
+/// keep their time, keycode and type, so the token of the event passed to
+/// [`ImeClient::process_event`], see [`ImeClient::last_forwarded_token`], equals the token of the
+/// event the IME server sent back. Tokens of distinct key events only collide if the X server
+/// reported the same time for them, e.g. for autorepeated keys of some X servers. Whether the IME
+/// server sends a key event back at all is up to the IME server.
+#[cfg(feature = "forward")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyToken {
+    time: u32,
+    keycode: u8,
+    pressed: bool,
+}
+
+#[cfg(feature = "forward")]
+impl KeyToken {
+    /// Token of a key press or key release event, `None` for other events.
+    pub fn of(event: &xcb::Event) -> Option<Self> {
+        match event {
+            xcb::Event::X(xcb::x::Event::KeyPress(event)) => Some(Self {
+                time: event.time(),
+                keycode: event.detail(),
+                pressed: true,
+            }),
+            xcb::Event::X(xcb::x::Event::KeyRelease(event)) => Some(Self {
+                time: event.time(),
+                keycode: event.detail(),
+                pressed: false,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Input style and preedit attributes activated together, see
+/// [`ImeClient::register_style_preset`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StylePreset {
+    /// Input style to request, see [`ImeClient::set_input_style`].
+    pub style: InputStyle,
+    /// Foreground and background pixel values, see [`ImeClient::set_preedit_colors`].
+    pub colors: Option<(u32, u32)>,
+    /// Base font names, see [`ImeClient::set_preedit_font_set`].
+    pub font_set: Option<String>,
+    /// Line spacing in pixels, see [`ImeClient::set_preedit_line_space`].
+    pub line_space: Option<u32>,
+}
+
+impl StylePreset {
+    /// Create a preset that only changes the input style.
+    pub fn new(style: InputStyle) -> Self {
+        Self {
+            style,
+            colors: None,
+            font_set: None,
+            line_space: None,
+        }
+    }
+}
+
+/// Encoding of the text exchanged with the IME server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// X11 Compound Text, converted to UTF-8 by this crate.
+    CompoundText,
+    /// UTF-8.
+    Utf8String,
+}
+
+impl Encoding {
+    fn from_raw(encoding: xcb_xim_encoding_t) -> Option<Self> {
+        #[allow(non_upper_case_globals)]
+        match encoding {
+            _xcb_xim_encoding_t_XCB_XIM_COMPOUND_TEXT => Some(Self::CompoundText),
+            _xcb_xim_encoding_t_XCB_XIM_UTF8_STRING => Some(Self::Utf8String),
+            _ => None,
+        }
+    }
+
+    /// Decode a string in this encoding the way committed strings are decoded.
+    ///
+    /// This is meant for fuzzing the decoding of strings sent by IME servers without a running
+    /// IME server, e.g. with `cargo fuzz`. Invalid UTF-8 is replaced by U+FFFD.
+    #[cfg(feature = "debug-tools")]
+    pub fn decode(self, bytes: &[u8]) -> String {
+        let mut buf = Vec::new();
+        decode_xim_slice(Some(self), bytes, &mut buf);
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+}
+
+/// Lifecycle state of the connection to the IME server, see [`ImeClient::state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImeState {
+    /// No attempt to open the IME has been made yet, or no IME server could be found so far.
+    Uninitialized,
+    /// An IME server has been found and the connection is being established.
+    Opening,
+    /// The IME is open, input contexts can be created.
+    Open,
+    /// The connection to the IME server has been lost.
+    Disconnected,
+}
+
+/// How [`ImeClient::set_input_style`] applied the input style.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StyleChange {
+    /// No input context exists yet, the input style is used once it is created.
+    Deferred,
+    /// The input context has been destroyed and a new one with the input style is being created.
+    Recreated,
+}
+
+/// Error of [`ImeClient::set_input_style`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImeError {
+    /// The IME server does not support the input style, see [`ImeClient::supported_styles`].
+    /// Nothing has been changed.
+    UnsupportedStyle(InputStyle),
+    /// The old input context has been destroyed, but the request to create the new one could not
+    /// be sent. Creating it is attempted again like after losing the connection, i.e. on the next
+    /// key event or call to [`ImeClient::update_pos`].
+    CreateIcFailed,
+}
+
+impl std::fmt::Display for ImeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedStyle(style) => {
+                write!(f, "input style {} not supported by the IME server", style)
+            }
+            Self::CreateIcFailed => f.write_str("failed to request a new input context"),
+        }
+    }
+}
+
+impl std::error::Error for ImeError {}
+
+/// Capabilities the IME server announced when the IME was opened, see
+/// [`ImeClient::server_capabilities`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    /// Encoding negotiated with the IME server.
+    pub encoding: Option<Encoding>,
+    /// Names of the supported XIM extensions, e.g. `XIM_EXT_MOVE`.
+    pub extensions: Vec<String>,
+}
+
+/// Result of the health check performed by [`ImeClient::self_test`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    /// Whether the X connection is free of errors, `None` if the [`ImeClient`] does not own the
+    /// [`xcb::Connection`], see [`ImeClient::from_borrowed`].
+    pub connection_alive: Option<bool>,
+    /// Whether xcb-imdkit created its XIM handle.
+    pub im_created: bool,
+    /// Whether compound text conversion works, see [`ImeClient::compound_text_available`].
+    pub compound_text: bool,
+    /// Lifecycle state of the connection to the IME server, [`ImeState::Uninitialized`] if no
+    /// IME server has been found yet.
+    pub state: ImeState,
+    /// Encoding negotiated with the IME server, see [`ImeClient::encoding`].
+    pub encoding: Option<Encoding>,
+    /// Input styles supported by the IME server, see [`ImeClient::supported_styles`].
+    pub supported_styles: Option<Vec<InputStyle>>,
+    /// Input style of the current input context, see [`ImeClient::active_style`].
+    pub active_style: Option<InputStyle>,
+}
+
+/// Upper bound for the length of XIM messages, whose length is given as CARD16 in 4 byte units.
+const MAX_XIM_MESSAGE_BYTES: usize = 4 + 4 * u16::MAX as usize;
+
+/// Flag of `XIM_ERROR` marking the input-context-ID as valid.
+const XIM_ERROR_IC_VALID: u16 = 0x0002;
+
+/// `XIM_ERROR` codes taken as sign of an unknown input context, see
+/// [`ImeClient::set_ic_destroyed_cb`].
+const XIM_BAD_PROTOCOL: u16 = 13;
+const XIM_BAD_SOMETHING: u16 = 999;
+
+/// XIM extensions known to xcb-imdkit with their minor opcode.
+const XIM_EXTENSIONS: &[(&str, u32)] = &[
+    ("XIM_EXT_SET_EVENT_MASK", XCB_XIM_EXT_SET_EVENT_MASK),
+    ("XIM_EXT_FORWARD_KEYEVENT", XCB_XIM_EXT_FORWARD_KEYEVENT),
+    ("XIM_EXT_MOVE", XCB_XIM_EXT_MOVE),
+];
+
+/// Information about the input context of an [`ImeClient`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcInfo {
+    /// Window the input context belongs to, as set by [`ImeClient::update_pos`].
+    pub window: Window,
+    /// Whether the input context has the input focus.
+    pub focused: bool,
+    /// Input style granted by the IME server, see [`ImeClient::active_style`].
+    pub input_style: InputStyle,
+}
+
+/// Counters of the events handled by an [`ImeClient`], see [`ImeClient::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImeStats {
+    /// Strings committed, including those of client-side composition.
+    pub commits: u64,
+    /// Key events forwarded to the IME server by [`ImeClient::process_event`].
+    pub forwarded: u64,
+    /// Key events the IME server sent back unhandled.
+    pub forwarded_back: u64,
+    /// Events consumed as XIM protocol messages by [`ImeClient::process_event`].
+    pub filtered_events: u64,
+    /// Successful connections to the IME server.
+    pub opens: u64,
+    /// Losses of the connection to the IME server.
+    pub disconnects: u64,
+}
+
+/// Snapshot of the configuration of an [`ImeClient`], see [`ImeClient::config_snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImeSnapshot {
+    /// Name of the IME server, see [`ImeClient::im_name`].
+    pub im_name: Option<String>,
+    /// Screen the IME client was created for, see [`ImeClient::screen_id`].
+    pub screen_id: i32,
+    /// Encoding negotiated with the IME server, see [`ImeClient::encoding`].
+    pub encoding: Option<Encoding>,
+    /// Input style requested by the application.
+    pub input_style: InputStyle,
+    /// Input styles supported by the IME server, see [`ImeClient::supported_styles`].
+    pub supported_styles: Option<Vec<InputStyle>>,
+    /// Whether Compound Text is offered to the IME server as encoding.
+    pub use_compound_text: bool,
+    /// Whether UTF-8 is offered to the IME server as encoding.
+    pub use_utf8_string: bool,
+    /// State of the input context, see [`ImeClient::ic_info`].
+    pub ic: Option<IcInfo>,
+}
+
 type StringCB = dyn for<'a> FnMut(Window, &'a str);
+type StateCB = dyn FnMut(ImeState);
+type StallCB = dyn FnMut(Duration);
+type Utf16CB = dyn FnMut(Window, Vec<u16>);
+type CommitCB = dyn FnMut(Window, CommitString);
+type DispatchCB = dyn FnMut(ImeEvent);
+type RawCommitCB = dyn for<'a> FnMut(Window, Option<Encoding>, &'a [u8]);
+type MessageTapCB = dyn for<'a> FnMut(u8, &'a [u8]);
+#[cfg(feature = "forward")]
 type KeyPressCB = dyn for<'a> FnMut(Window, &'a xcb::Event);
+#[cfg(feature = "forward")]
+type ForwardedKeyCB = dyn FnMut(ForwardedKey);
+#[cfg(feature = "preedit")]
 type PreeditDrawCB = dyn for<'a> FnMut(Window, PreeditInfo<'a>);
 type NotifyCB = dyn FnMut(Window);
+#[cfg(feature = "preedit")]
+type CompositionCB = dyn FnMut(Window, CompositionUpdate);
+#[cfg(feature = "preedit")]
+type PreeditPhaseCB = dyn FnMut(Window, PreeditPhase);
 
 #[derive(Default)]
 struct Callbacks {
     commit_string: Option<Box<StringCB>>,
+    #[cfg(feature = "forward")]
     forward_event: Option<Box<KeyPressCB>>,
+    #[cfg(feature = "forward")]
+    forward_key: Option<Box<ForwardedKeyCB>>,
+    #[cfg(feature = "preedit")]
     preedit_start: Option<Box<NotifyCB>>,
+    #[cfg(feature = "preedit")]
     preedit_draw: Option<Box<PreeditDrawCB>>,
+    #[cfg(feature = "preedit")]
     preedit_done: Option<Box<NotifyCB>>,
+    #[cfg(feature = "preedit")]
+    composition: Option<Box<CompositionCB>>,
+    #[cfg(feature = "preedit")]
+    preedit_phase: Option<Box<PreeditPhaseCB>>,
+    #[cfg(feature = "status")]
+    status_draw: Option<Box<StringCB>>,
+    message_tap: Option<Box<MessageTapCB>>,
+    state_changed: Option<Box<StateCB>>,
+    commit_raw: Option<Box<RawCommitCB>>,
+    server_stalled: Option<Box<StallCB>>,
+    commit_utf16: Option<Box<Utf16CB>>,
+    commit: Option<Box<CommitCB>>,
+    dispatch: Option<Box<DispatchCB>>,
+    ic_destroyed: Option<Box<NotifyCB>>,
+}
+
+/// Event emitted by the IME, see [`ImeClient::drain_events`].
+#[derive(Debug)]
+pub enum ImeEvent {
+    /// Input composition is done, see [`ImeClient::set_commit_string_cb`].
+    Commit { win: Window, text: String },
+    /// Key event unhandled by the IME, see [`ImeClient::set_forward_event_cb`].
+    #[cfg(feature = "forward")]
+    Forward { win: Window, event: xcb::Event },
+    /// The IME has been opened, see [`ImeClient::set_preedit_start_cb`].
+    #[cfg(feature = "preedit")]
+    PreeditStart { win: Window },
+    /// The text within the IME has changed, see [`ImeClient::set_preedit_draw_cb`].
+    #[cfg(feature = "preedit")]
+    PreeditChanged { win: Window, preedit: PreeditString },
+    /// The IME has been closed, see [`ImeClient::set_preedit_done_cb`].
+    #[cfg(feature = "preedit")]
+    PreeditDone { win: Window },
+    /// The status of the IME has changed, see [`ImeClient::set_status_draw_cb`].
+    #[cfg(feature = "status")]
+    StatusChanged { win: Window, text: String },
+    /// The connection to the IME server has been lost.
+    Disconnected,
+    /// The lifecycle state has changed, see [`ImeClient::set_state_changed_cb`].
+    StateChanged { state: ImeState },
+    /// The IME server stopped responding, see [`ImeClient::set_server_stalled_cb`].
+    ServerStalled { elapsed: Duration },
+    /// The IME server destroyed the input context, see [`ImeClient::set_ic_destroyed_cb`].
+    IcDestroyed { win: Window },
+}
+
+/// What [`ImeClient::filter_event`] did with an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Filtered {
+    /// The event is not meant for the IME client and has to be handled by the application.
+    Ignored,
+    /// xcb-imdkit consumed the event, e.g. an XIM message of the IME server.
+    Consumed,
+    /// The key event has been forwarded to the IME or is kept by the IME client.
+    Used,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -279,11 +1130,13 @@ struct ImePos {
 /// [`PreeditInfo`] provides information about the text that is currently being edited by the IME.
 ///
 /// Additionally it provides information about how the text has been changed.
+#[cfg(feature = "preedit")]
 pub struct PreeditInfo<'a> {
-    im: *mut xcb_xim_t,
-    inner: &'a xcb_im_preedit_draw_fr_t,
+    draw: &'a PreeditString,
+    current: &'a PreeditString,
 }
 
+#[cfg(feature = "preedit")]
 impl<'a> PreeditInfo<'a> {
     /// Status bitmask.
     ///
@@ -294,48 +1147,412 @@ impl<'a> PreeditInfo<'a> {
     ///
     /// [`text`]: PreeditInfo::text
     pub fn status(&self) -> u32 {
-        self.inner.status
+        self.draw.status
     }
 
     /// Cursor offset within the currently edited text in characters.
     pub fn caret(&self) -> u32 {
-        self.inner.caret
+        self.draw.caret
     }
 
     /// Starting change position.
     pub fn chg_first(&self) -> u32 {
-        self.inner.chg_first
+        self.draw.chg_first
     }
 
     /// Length of the change counting characters.
     pub fn chg_length(&self) -> u32 {
-        self.inner.chg_length
+        self.draw.chg_length
     }
 
     /// Current text in the IME.
+    ///
+    /// The text is truncated to the limit set with [`ImeClient::set_max_preedit_bytes`].
     pub fn text(&self) -> String {
-        unsafe {
-            xim_encoding_to_utf8(
-                self.im,
-                self.inner.preedit_string as _,
-                self.inner.length_of_preedit_string as usize,
-            )
-        }
+        self.draw.text.clone()
+    }
+
+    /// Return `true` if [`text`] has been truncated.
+    ///
+    /// [`text`]: PreeditInfo::text
+    pub fn truncated(&self) -> bool {
+        self.draw.truncated
     }
 
     /// Feedback information to each character of preedit text.
     /// Refer to [`InputFeedback`] for more details.
     pub fn feedback_array(&self) -> &[u32] {
-        unsafe {
-            from_raw_parts(
-                self.inner.feedback_array.items,
-                self.inner.feedback_array.size as usize,
-            )
-        }
+        &self.draw.feedback_array
     }
-}
 
-/// Wrapper around std::slice::from_raw_parts that allows for ptr to be
+    /// The whole preedit with this change applied.
+    ///
+    /// The IME server only sends the part of the preedit that changed; the [`ImeClient`] keeps
+    /// track of the whole preedit by applying each change, see [`PreeditString::apply_draw`].
+    pub fn current(&self) -> &PreeditString {
+        self.current
+    }
+
+    /// Copy the preedit information into a [`PreeditString`] that is not bound to the callback.
+    pub fn to_preedit_string(&self) -> PreeditString {
+        self.draw.clone()
+    }
+}
+
+/// Owned version of [`PreeditInfo`], see [`ImeEvent::PreeditChanged`].
+#[cfg(feature = "preedit")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreeditString {
+    /// Status bitmask, see [`PreeditInfo::status`].
+    pub status: u32,
+    /// Cursor offset within the currently edited text in characters.
+    pub caret: u32,
+    /// Starting change position.
+    pub chg_first: u32,
+    /// Length of the change counting characters.
+    pub chg_length: u32,
+    /// Current text in the IME.
+    pub text: String,
+    /// Whether `text` has been truncated, see [`PreeditInfo::truncated`].
+    pub truncated: bool,
+    /// Feedback information to each character of preedit text, see [`InputFeedback`].
+    pub feedback_array: Vec<u32>,
+}
+
+#[cfg(feature = "preedit")]
+impl PreeditString {
+    /// Decode a preedit draw of the IME server, truncating its text to `max_bytes`.
+    unsafe fn from_draw(
+        im: *mut xcb_xim_t,
+        draw: &xcb_im_preedit_draw_fr_t,
+        max_bytes: usize,
+    ) -> Self {
+        let mut text = xim_encoding_to_utf8(
+            im,
+            draw.preedit_string as _,
+            draw.length_of_preedit_string as usize,
+        );
+        let truncated = truncate_preedit(&mut text, max_bytes);
+        PreeditString {
+            status: draw.status,
+            caret: draw.caret,
+            chg_first: draw.chg_first,
+            chg_length: draw.chg_length,
+            text,
+            truncated,
+            feedback_array: from_raw_parts(
+                draw.feedback_array.items,
+                draw.feedback_array.size as usize,
+            )
+            .to_vec(),
+        }
+    }
+
+    /// Apply a preedit change sent by the IME server to this preedit.
+    ///
+    /// The `chg_length` characters starting at `chg_first` are replaced by the text of `draw`,
+    /// which covers insertions (`chg_length` is `0`), deletions (`draw` has no text) and
+    /// replacements. The feedback is updated the same way and the caret is taken from `draw`.
+    /// Positions beyond the end of the preedit are clamped. If the resulting text exceeds
+    /// `max_bytes`, it is truncated at a character boundary and `truncated` is set.
+    pub fn apply_draw(&mut self, draw: &PreeditString, max_bytes: usize) {
+        let byte_offset = |s: &str, chars: usize| {
+            s.char_indices()
+                .nth(chars)
+                .map_or(s.len(), |(offset, _)| offset)
+        };
+        // status bit 0x01 signals that there is no text, 0x02 that there is no feedback
+        let text = if draw.status & 0x01 == 0 {
+            draw.text.as_str()
+        } else {
+            ""
+        };
+        let inserted = text.chars().count();
+        self.feedback_array.resize(self.text.chars().count(), 0);
+        let start = byte_offset(&self.text, draw.chg_first as usize);
+        let end = start + byte_offset(&self.text[start..], draw.chg_length as usize);
+        self.text.replace_range(start..end, text);
+        let first = (draw.chg_first as usize).min(self.feedback_array.len());
+        let last = (first + draw.chg_length as usize).min(self.feedback_array.len());
+        let feedback = if draw.status & 0x02 == 0 {
+            &draw.feedback_array[..]
+        } else {
+            &[]
+        };
+        let feedback = feedback
+            .iter()
+            .copied()
+            .chain(std::iter::repeat(0))
+            .take(inserted);
+        self.feedback_array.splice(first..last, feedback);
+        if truncate_preedit(&mut self.text, max_bytes) {
+            self.feedback_array.truncate(self.text.chars().count());
+            self.truncated = true;
+        }
+        self.status = if self.text.is_empty() { 0x01 } else { 0 };
+        self.caret = draw.caret;
+        self.chg_first = draw.chg_first;
+        self.chg_length = draw.chg_length;
+        self.truncated |= draw.truncated;
+    }
+}
+
+/// Truncate `text` to at most `max_bytes` at a character boundary.
+///
+/// Return whether `text` has been truncated.
+#[cfg(feature = "preedit")]
+fn truncate_preedit(text: &mut String, max_bytes: usize) -> bool {
+    if text.len() <= max_bytes {
+        return false;
+    }
+    let mut len = max_bytes;
+    while !text.is_char_boundary(len) {
+        len -= 1;
+    }
+    log(&format!(
+        "preedit of {} bytes truncated to {} bytes",
+        text.len(),
+        len
+    ));
+    text.truncate(len);
+    true
+}
+
+/// Handling of control characters within committed strings, see
+/// [`ImeClient::set_control_char_policy`].
+///
+/// Control characters are the code points of the Unicode category `Cc`, i.e. U+0000 to U+001F
+/// and U+007F to U+009F, which include newline and tab.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ControlCharPolicy {
+    /// Pass control characters on unchanged.
+    #[default]
+    Keep,
+    /// Remove control characters.
+    Strip,
+    /// Replace control characters by Rust escape sequences like `\n` or `\u{1b}`.
+    Escape,
+}
+
+impl ControlCharPolicy {
+    fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if *self == ControlCharPolicy::Keep || !text.chars().any(char::is_control) {
+            return Cow::Borrowed(text);
+        }
+        let mut res = String::with_capacity(text.len());
+        for c in text.chars() {
+            if !c.is_control() {
+                res.push(c);
+            } else if *self == ControlCharPolicy::Escape {
+                res.extend(c.escape_default());
+            }
+        }
+        Cow::Owned(res)
+    }
+}
+
+/// Classification of a [`CommitString`] by its content.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CommitKind {
+    /// The string contains characters other than whitespace.
+    Text,
+    /// The string is empty, i.e. the composition produced nothing.
+    #[default]
+    Empty,
+    /// The string consists of whitespace only, which some IMEs commit when a composition ends.
+    Whitespace,
+}
+
+impl CommitKind {
+    fn of(text: &str) -> Self {
+        if text.is_empty() {
+            CommitKind::Empty
+        } else if text.chars().all(char::is_whitespace) {
+            CommitKind::Whitespace
+        } else {
+            CommitKind::Text
+        }
+    }
+}
+
+/// String committed by the IME, see [`ImeClient::set_commit_cb`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommitString {
+    /// The completed input.
+    pub text: String,
+    /// Whether `text` is empty, whitespace only or contains actual text.
+    pub kind: CommitKind,
+    /// Length in bytes of `text` as delivered, i.e. after replacing invalid UTF-8 sequences and
+    /// applying the [`ControlCharPolicy`]. This is not the length sent by the IME server, which
+    /// is available through [`ImeClient::set_commit_raw_cb`].
+    pub byte_len_hint: usize,
+}
+
+/// Start or end of a composition, see [`ImeClient::set_preedit_phase_cb`].
+#[cfg(feature = "preedit")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PreeditPhase {
+    /// The IME started drawing the preedit, see [`ImeClient::set_preedit_start_cb`].
+    Start,
+    /// The IME stopped drawing the preedit, see [`ImeClient::set_preedit_done_cb`].
+    Stop,
+}
+
+/// Commit and preedit update that happened together, see [`ImeClient::set_composition_cb`].
+#[cfg(feature = "preedit")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompositionUpdate {
+    /// Text committed right before the preedit update.
+    pub committed: Option<String>,
+    /// Change of the preedit text, `None` if the commit was not followed by a preedit update.
+    pub preedit: Option<PreeditString>,
+}
+
+/// XIM nested list of IC attributes whose memory is owned by Rust.
+///
+/// xcb-imdkit serializes each attribute into a self-contained, padded entry, so lists built from
+/// single attributes can be concatenated. This allows to build lists whose set of attributes is
+/// only known at runtime.
+#[derive(Default)]
+struct NestedList {
+    data: Vec<u8>,
+}
+
+impl NestedList {
+    /// Append the attribute `name` with the value pointed to by `value`.
+    ///
+    /// The type of `value` has to match the type xcb-imdkit expects for `name`. If xcb-imdkit
+    /// fails to serialize the attribute, it is logged and left out.
+    unsafe fn push<T>(&mut self, im: *mut xcb_xim_t, name: &[u8], value: *const T) {
+        let nested =
+            xcb_xim_create_nested_list(im, name.as_ptr(), value, std::ptr::null_mut::<c_void>());
+        if nested.data.is_null() {
+            let name = std::ffi::CStr::from_bytes_with_nul(name).unwrap_or_default();
+            log(&format!(
+                "failed to create nested list for attribute {}",
+                name.to_string_lossy()
+            ));
+            return;
+        }
+        self.data.extend(from_raw_parts(nested.data, nested.length));
+        free(nested.data as _);
+    }
+
+    /// Append the attribute `attr` with a value of the type xcb-imdkit expects for it.
+    fn push_attr(&mut self, im: *mut xcb_xim_t, attr: &IcAttr) {
+        unsafe {
+            match attr {
+                IcAttr::Spot(spot) => {
+                    self.push(im, XCB_XIM_XNSpotLocation, &xcb_point_t::from(*spot))
+                }
+                IcAttr::Area(area) => self.push(im, XCB_XIM_XNArea, &xcb_rectangle_t::from(*area)),
+                IcAttr::Foreground(pixel) => self.push(im, XCB_XIM_XNForeground, pixel),
+                IcAttr::Background(pixel) => self.push(im, XCB_XIM_XNBackground, pixel),
+                IcAttr::BackgroundPixmap(pixmap) => {
+                    self.push(im, XCB_XIM_XNBackgroundPixmap, pixmap)
+                }
+                IcAttr::LineSpace(space) => self.push(im, XCB_XIM_XNLineSpace, space),
+                // the font set is passed as C string rather than as pointer to a value
+                IcAttr::FontSet(name) => match CString::new(name.as_str()) {
+                    Ok(name) => self.push(im, XCB_XIM_XNFontSet, name.as_ptr()),
+                    Err(_) => log("font set name contains a NUL byte"),
+                },
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn as_raw(&mut self) -> xcb_xim_nested_list {
+        xcb_xim_nested_list {
+            data: self.data.as_mut_ptr(),
+            length: self.data.len(),
+        }
+    }
+}
+
+/// Return the name and value of the status attributes argument for `xcb_xim_set_ic_values` and
+/// `xcb_xim_create_ic`.
+///
+/// The status attributes are passed last. If there are none, the name is null, which terminates
+/// the argument list early.
+fn status_attribute(list: &mut NestedList) -> (*const u8, xcb_xim_nested_list) {
+    let name = if list.is_empty() {
+        std::ptr::null()
+    } else {
+        XCB_XIM_XNStatusAttributes.as_ptr()
+    };
+    (name, list.as_raw())
+}
+
+/// Typed attribute of the preedit or status attributes of an input context.
+///
+/// All attributes are encoded by [`NestedList::push_attr`], which passes xcb-imdkit a value of
+/// the right type for every attribute name. `XNPreeditState` is not supported by xcb-imdkit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum IcAttr {
+    /// `XNSpotLocation`, the position of the cursor.
+    Spot(Point),
+    /// `XNArea`, the area to draw in.
+    Area(Rectangle),
+    /// `XNForeground`, a pixel value.
+    Foreground(u32),
+    /// `XNBackground`, a pixel value.
+    Background(u32),
+    /// `XNBackgroundPixmap`, a pixmap id.
+    BackgroundPixmap(u32),
+    /// `XNFontSet`, a comma separated list of base font names.
+    FontSet(String),
+    /// `XNLineSpace`, the distance between two lines in pixels.
+    LineSpace(u32),
+}
+
+/// Preedit attributes that are sent to the IME in addition to the spot location.
+#[derive(Debug, Clone, Default)]
+struct PreeditAttrs {
+    foreground: Option<u32>,
+    background: Option<u32>,
+    background_pixmap: Option<u32>,
+    font_set: Option<String>,
+    line_space: Option<u32>,
+}
+
+impl PreeditAttrs {
+    fn push_to(&self, im: *mut xcb_xim_t, list: &mut NestedList) {
+        let attrs = [
+            self.foreground.map(IcAttr::Foreground),
+            self.background.map(IcAttr::Background),
+            self.background_pixmap.map(IcAttr::BackgroundPixmap),
+            self.font_set.clone().map(IcAttr::FontSet),
+            self.line_space.map(IcAttr::LineSpace),
+        ];
+        for attr in attrs.iter().flatten() {
+            list.push_attr(im, attr);
+        }
+    }
+}
+
+/// Compute pixel value of a color component for a visual with the given channel `mask`.
+fn scale_to_mask(value: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let max = (mask >> shift) as u64;
+    ((value as u64 * max / 255) as u32) << shift
+}
+
+/// Read a CARD32 from the start of `bytes`.
+///
+/// XIM servers send data in the byte order of the client, i.e. native byte order.
+fn read_card32(bytes: &[u8]) -> Option<u32> {
+    let bytes = bytes.get(..4)?;
+    Some(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Wrapper around std::slice::from_raw_parts that allows for ptr to be
 /// null. In the null ptr case, an empty slice is returned.
 /// This is necessary because it is common for C programs to encode
 /// empty arrays in that way, and rust 1.78 will panic if a null
@@ -348,6 +1565,7 @@ unsafe fn from_raw_parts<'a, T>(ptr: *const T, size: usize) -> &'a [T] {
     }
 }
 
+#[cfg(feature = "preedit")]
 impl<'a> std::fmt::Debug for PreeditInfo<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("PreeditInfo")
@@ -368,26 +1586,157 @@ impl<'a> std::fmt::Debug for PreeditInfo<'a> {
 /// IME client per application and it is advised to create at most one instance.
 pub struct ImeClient {
     conn: Option<Arc<xcb::Connection>>,
+    raw_conn: *mut xcb_connection_t,
     im: *mut xcb_xim_t,
+    im_name: Option<CString>,
+    state: ImeState,
     ic: Option<xcb_xic_t>,
+    ic_pending: bool,
+    ic_focused: bool,
+    focus_req: bool,
+    stats: ImeStats,
+    control_char_policy: ControlCharPolicy,
+    last_commits: Vec<(u32, String)>,
+    #[cfg(feature = "forward")]
+    last_forwarded: Option<KeyToken>,
+    #[cfg(feature = "status")]
+    status_text: Option<(Window, String)>,
+    active_style: Option<InputStyle>,
+    supported_styles: Option<Vec<InputStyle>>,
+    use_compound_text: bool,
+    use_utf8_string: bool,
     callbacks: Callbacks,
     input_style: InputStyle,
+    ic_style: InputStyle,
     pos_cur: ImePos,
     pos_req: ImePos,
     is_processing_pos_update: bool,
     pos_update_queued: bool,
+    open_retries: u32,
+    open_retry_delay: Duration,
+    open_attempt: u32,
+    next_open_attempt: Option<Instant>,
+    events: Option<Vec<ImeEvent>>,
+    forward_event_mask: u32,
+    screen_id: i32,
+    preedit_attrs: PreeditAttrs,
+    pixel_cache: HashMap<(u8, u8, u8), u32>,
+    key_symbols: Option<KeySymbols>,
+    passthrough_keys: HashSet<(u32, u16)>,
+    disabled_windows: HashSet<u32>,
+    ignore_synthetic_keys: bool,
+    style_presets: HashMap<String, StylePreset>,
+    auto_flush: bool,
+    requests_pending: bool,
+    forward_releases: bool,
+    suspend_during_grab: bool,
+    grab_suspended: bool,
+    grab_commits: Vec<(Window, String)>,
+    rehome_on_configure: bool,
+    area_req: Option<xcb_rectangle_t>,
+    area_cur: Option<xcb_rectangle_t>,
+    status_area_req: Option<xcb_rectangle_t>,
+    status_area_cur: Option<xcb_rectangle_t>,
+    preedit_attrs_pending: bool,
+    #[cfg(feature = "preedit")]
+    pending_composition_commit: Option<(Window, String)>,
+    #[cfg(feature = "preedit")]
+    preedit: PreeditString,
+    message_atoms: Option<(xcb::x::Atom, xcb::x::Atom)>,
+    message_buffer: Vec<u8>,
+    #[cfg(feature = "compose")]
+    compose_table: Option<ComposeTable>,
+    #[cfg(feature = "compose")]
+    compose_pending: Vec<u32>,
+    spot_readback: Option<(i16, i16)>,
+    spot_readback_pending: bool,
+    #[cfg(feature = "preedit")]
+    visible_position_reply: Option<u32>,
+    #[cfg(feature = "preedit")]
+    visible_position_pending: bool,
+    reset_reply: Option<String>,
+    reset_pending: bool,
+    deferred_events: Vec<xcb::Event>,
+    last_handled_event: Instant,
+    commit_buffer: Vec<u8>,
+    #[cfg(feature = "preedit")]
+    max_preedit_bytes: usize,
+    waiting_for_commit: bool,
+    awaited_commit: Option<(Window, String)>,
+    stall_timeout: Option<Duration>,
+    ic_create_timeout: Option<Duration>,
+    ic_requested_at: Option<Instant>,
+    comm_window: Option<Window>,
+    replay_first_key: bool,
+    replay_key: Option<xcb_key_press_event_t>,
+    unanswered_since: Option<Instant>,
+    stall_reported: bool,
+    dedup_commits: bool,
+    last_commit: Option<(Window, String, Instant)>,
+    #[cfg(feature = "debug-tools")]
+    forced_encoding: Option<Encoding>,
 }
 
+/// Upper bound for the delay between two attempts to open the IME, see
+/// [`ImeClient::set_open_retries`].
+const OPEN_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Time to wait for a reply of the IME server when waiting synchronously.
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Time without events for the IME client after which [`ImeClient::drain_until_idle`] returns.
+const IDLE_PERIOD: Duration = Duration::from_millis(50);
+
+/// Default for [`ImeClient::set_max_preedit_bytes`].
+#[cfg(feature = "preedit")]
+const DEFAULT_MAX_PREEDIT_BYTES: usize = 64 * 1024;
+
+/// Time within which an identical commit is dropped, see [`ImeClient::set_dedup_commits`].
+const COMMIT_DEDUP_WINDOW: Duration = Duration::from_millis(20);
+
+/// Time to sleep between polls for events when waiting synchronously.
+const PUMP_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Number of windows whose last commit is retained, see [`ImeClient::last_commit`].
+const LAST_COMMIT_MAX_WINDOWS: usize = 16;
+
+/// Size of the largest commit that is retained, see [`ImeClient::last_commit`].
+const LAST_COMMIT_MAX_BYTES: usize = 4 * 1024;
+
 impl ImeClient {
     /// Set the global logger for xcb-imdkit.
     ///
     /// The callback will receive debug messages from the [C
     /// library](https://github.com/fcitx/xcb-imdkit) this crate is wrapping.
+    ///
+    /// The callback is called synchronously on the thread that produced the message, i.e. all
+    /// messages logged during a call to [`process_event`] or any other method have been passed to
+    /// the callback before the method returns, in the order they were logged. Messages logged from
+    /// within another callback are delivered before that callback continues. The logger is shared
+    /// by all [`ImeClient`]s and calls to it are serialized, see [`flush_logs`].
+    ///
+    /// A panic within the callback does not propagate: the message is dropped and later messages
+    /// are still passed to the callback.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`flush_logs`]: ImeClient::flush_logs
     pub fn set_logger<F>(f: F)
     where
         F: for<'a> FnMut(&'a str) + Send + 'static,
     {
-        LOGGER.lock().unwrap().replace(Box::new(f));
+        lock_logger().replace(Box::new(f));
+    }
+
+    /// Wait until the logger has returned from all messages that are currently being logged.
+    ///
+    /// Messages of the calling thread are always delivered before the logging method returns, so
+    /// this only matters if [`ImeClient`]s are used on several threads: once this returns, every
+    /// message logged by another thread before the call has been passed to the logger set with
+    /// [`set_logger`].
+    ///
+    /// [`set_logger`]: ImeClient::set_logger
+    pub fn flush_logs() {
+        drop(lock_logger());
     }
 
     /// Create a new [`ImeClient`].
@@ -399,7 +1748,20 @@ impl ImeClient {
     /// `im_name` can be used to specify a custom IME server to connect to using the syntax
     /// `@im=custom_server`.
     ///
+    /// The input context is created once the window is known from [`update_pos`]. Preedit
+    /// attributes set before, i.e. with [`set_preedit_colors`], [`set_preedit_background_pixmap`]
+    /// and [`set_initial_preedit_area`], are sent along with the request creating the input
+    /// context. Some IME servers ignore these attributes once the input context exists.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `im_name` contains a NUL byte.
+    ///
     /// [`Arc`]: std::sync::Arc
+    /// [`update_pos`]: ImeClient::update_pos
+    /// [`set_preedit_colors`]: ImeClient::set_preedit_colors
+    /// [`set_preedit_background_pixmap`]: ImeClient::set_preedit_background_pixmap
+    /// [`set_initial_preedit_area`]: ImeClient::set_initial_preedit_area
     pub fn new(
         conn: Arc<xcb::Connection>,
         screen_id: i32,
@@ -411,6 +1773,38 @@ impl ImeClient {
         res
     }
 
+    /// Create a new [`ImeClient`] connected to the first available IME server out of `im_names`.
+    ///
+    /// The IME servers are tried in the given order, each name using the same syntax as the
+    /// `im_name` argument of [`new`], e.g. `&["@im=fcitx5", "@im=fcitx", "@im=ibus"]`. Unlike
+    /// [`new`], the IME is opened right away to find out whether the server is available. The
+    /// selected server can be queried with [`im_name`].
+    ///
+    /// Return `None` if none of the IME servers could be found.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the names contains a NUL byte.
+    ///
+    /// [`new`]: ImeClient::new
+    /// [`im_name`]: ImeClient::im_name
+    pub fn new_with_fallback(
+        conn: Arc<xcb::Connection>,
+        screen_id: i32,
+        input_style: InputStyle,
+        im_names: &[&str],
+    ) -> Option<Pin<Box<Self>>> {
+        im_names.iter().find_map(|&name| {
+            let mut res = Self::new(conn.clone(), screen_id, input_style, Some(name));
+            // on failure `res` is dropped, which cleans up the xcb_xim_t
+            if res.open_im() {
+                Some(res)
+            } else {
+                None
+            }
+        })
+    }
+
     /// Create a new [`ImeClient`].
     ///
     /// This is the same as [`new`], except that the [`xcb::Connection`] is not wrapped
@@ -419,9 +1813,17 @@ impl ImeClient {
     /// # Safety
     ///
     /// The caller is responsible to ensure that the [`ImeClient`] does not outlive the connection.
+    /// Nothing ties the lifetime of the returned [`ImeClient`] to `conn`, dropping the connection
+    /// first results in a use after free. Prefer [`from_borrowed`], which lets the borrow checker
+    /// enforce this.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `im_name` contains a NUL byte.
     ///
     /// [`Arc`]: std::sync::Arc
     /// [`new`]: ImeClient::new
+    /// [`from_borrowed`]: ImeClient::from_borrowed
     pub unsafe fn unsafe_new(
         conn: &xcb::Connection,
         screen_id: i32,
@@ -429,177 +1831,2584 @@ impl ImeClient {
         im_name: Option<&str>,
     ) -> Pin<Box<Self>> {
         xcb_compound_text_init();
+        let im_name =
+            im_name.map(|name| CString::new(name).expect("im_name must not contain NUL bytes"));
         let im = xcb_xim_create(
             conn.get_raw_conn() as _,
             screen_id,
-            im_name.map_or(std::ptr::null(), |name| name.as_ptr() as _),
+            im_name
+                .as_ref()
+                .map_or(std::ptr::null(), |name| name.as_ptr()),
         );
         let mut res = Box::pin(Self {
             conn: None,
+            raw_conn: conn.get_raw_conn() as _,
             im,
+            im_name,
+            state: ImeState::Uninitialized,
             ic: None,
+            ic_pending: false,
+            ic_focused: false,
+            focus_req: true,
+            stats: ImeStats::default(),
+            control_char_policy: ControlCharPolicy::default(),
+            last_commits: Vec::new(),
+            #[cfg(feature = "forward")]
+            last_forwarded: None,
+            #[cfg(feature = "status")]
+            status_text: None,
+            active_style: None,
+            supported_styles: None,
+            use_compound_text: true,
+            use_utf8_string: true,
             callbacks: Callbacks::default(),
             input_style,
+            ic_style: input_style,
             pos_cur: ImePos { win: 0, x: 0, y: 0 },
             pos_req: ImePos { win: 0, x: 0, y: 0 },
             is_processing_pos_update: false,
             pos_update_queued: false,
+            open_retries: 0,
+            open_retry_delay: Duration::from_millis(100),
+            open_attempt: 0,
+            next_open_attempt: None,
+            events: None,
+            forward_event_mask: DEFAULT_FORWARD_EVENT_MASK,
+            screen_id,
+            preedit_attrs: PreeditAttrs::default(),
+            pixel_cache: HashMap::new(),
+            key_symbols: None,
+            passthrough_keys: HashSet::new(),
+            disabled_windows: HashSet::new(),
+            ignore_synthetic_keys: false,
+            style_presets: HashMap::new(),
+            auto_flush: true,
+            requests_pending: false,
+            forward_releases: true,
+            suspend_during_grab: false,
+            grab_suspended: false,
+            grab_commits: Vec::new(),
+            rehome_on_configure: false,
+            area_req: None,
+            area_cur: None,
+            status_area_req: None,
+            status_area_cur: None,
+            preedit_attrs_pending: false,
+            #[cfg(feature = "preedit")]
+            pending_composition_commit: None,
+            #[cfg(feature = "preedit")]
+            preedit: PreeditString::default(),
+            message_atoms: None,
+            message_buffer: Vec::new(),
+            #[cfg(feature = "compose")]
+            compose_table: None,
+            #[cfg(feature = "compose")]
+            compose_pending: Vec::new(),
+            spot_readback: None,
+            spot_readback_pending: false,
+            #[cfg(feature = "preedit")]
+            visible_position_reply: None,
+            #[cfg(feature = "preedit")]
+            visible_position_pending: false,
+            reset_reply: None,
+            reset_pending: false,
+            deferred_events: Vec::new(),
+            last_handled_event: Instant::now(),
+            commit_buffer: Vec::new(),
+            #[cfg(feature = "preedit")]
+            max_preedit_bytes: DEFAULT_MAX_PREEDIT_BYTES,
+            waiting_for_commit: false,
+            awaited_commit: None,
+            stall_timeout: None,
+            ic_create_timeout: None,
+            ic_requested_at: None,
+            comm_window: None,
+            replay_first_key: false,
+            replay_key: None,
+            unanswered_since: None,
+            stall_reported: false,
+            dedup_commits: false,
+            last_commit: None,
+            #[cfg(feature = "debug-tools")]
+            forced_encoding: None,
         });
         let callbacks = xcb_xim_im_callback {
+            set_event_mask: Some(set_event_mask_callback),
             disconnected: Some(disconnected_callback),
             commit_string: Some(commit_string_callback),
+            #[cfg(feature = "forward")]
             forward_event: Some(forward_event_callback),
+            #[cfg(feature = "preedit")]
             preedit_start: Some(preedit_start_callback),
+            #[cfg(feature = "preedit")]
             preedit_draw: Some(preedit_draw_callback),
+            #[cfg(feature = "preedit")]
             preedit_done: Some(preedit_done_callback),
+            #[cfg(feature = "status")]
+            status_draw_text: Some(status_draw_text_callback),
             ..Default::default()
         };
         let data: *mut Self = res.as_mut().get_mut();
         xcb_xim_set_im_callback(im, &callbacks, data as _);
         xcb_xim_set_log_handler(im, Some(xcb_log_wrapper));
-        xcb_xim_set_use_compound_text(im, true);
-        xcb_xim_set_use_utf8_string(im, true);
+        xcb_xim_set_use_compound_text(im, res.use_compound_text);
+        xcb_xim_set_use_utf8_string(im, res.use_utf8_string);
         res
     }
 
-    fn try_open_ic(&mut self) {
-        if self.ic.is_some() {
-            return;
+    /// Create a new [`ImeClient`] that borrows the connection.
+    ///
+    /// This is the same as [`unsafe_new`], except that the returned [`BorrowedImeClient`] is bound
+    /// to the lifetime of `conn`, so it cannot outlive the connection. Like [`unsafe_new`], methods
+    /// that need to send their own requests to the X server, e.g. [`set_passthrough_keys`], require
+    /// an [`ImeClient`] created with [`new`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `im_name` contains a NUL byte.
+    ///
+    /// [`unsafe_new`]: ImeClient::unsafe_new
+    /// [`set_passthrough_keys`]: ImeClient::set_passthrough_keys
+    /// [`new`]: ImeClient::new
+    pub fn from_borrowed<'c>(
+        conn: &'c xcb::Connection,
+        screen_id: i32,
+        input_style: InputStyle,
+        im_name: Option<&str>,
+    ) -> BorrowedImeClient<'c> {
+        BorrowedImeClient {
+            // the lifetime of `BorrowedImeClient` ensures that `conn` outlives the `ImeClient`
+            inner: unsafe { Self::unsafe_new(conn, screen_id, input_style, im_name) },
+            _conn: PhantomData,
         }
-        let data: *mut ImeClient = self as _;
-        unsafe { xcb_xim_open(self.im, Some(open_callback), true, data as _) };
     }
 
-    /// Let the IME client process XCB's events.
+    /// Retry opening the IME if no IME server could be found.
     ///
-    /// Return `true` if the IME client is handling the event and `false` if the event is ignored
-    /// by the IME client and has to be handled separately.
+    /// xcb-imdkit tries to find an IME server only once per attempt to open the IME, which may fail
+    /// if the server has not yet registered itself, e.g. right after login. If `retries` is
+    /// greater than zero, failed attempts are retried up to `retries` times. The first retry
+    /// happens after `initial_delay`, each following retry waits twice as long as the previous one
+    /// up to a maximum of 30 seconds.
     ///
-    /// This method should be called on **any** event from the event queue and not just
-    /// keypress/keyrelease events as it handles other events as well.
+    /// Retries are driven by [`process_event`], so they only happen while events arrive. By
+    /// default no retries are scheduled and the IME is only opened on key events and calls to
+    /// [`update_pos`].
     ///
-    /// Typically you will want to let the IME client handle all keypress/keyrelease events in your
-    /// main loop. The IME client will then forward all key events that were not used for input
-    /// composition to the callback set by [`set_forward_event_cb`]. Often those events include all
-    /// keyrelease events as well as the events for `ESC`, `Enter` or key combinations such as
-    /// `CTRL+C`.
-    /// To obtain the text currently typed into the IME and the final string consult
-    /// [`set_preedit_draw_cb`] and [`set_commit_string_cb`].
+    /// [`process_event`]: ImeClient::process_event
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn set_open_retries(&mut self, retries: u32, initial_delay: Duration) {
+        self.open_retries = retries;
+        self.open_retry_delay = initial_delay;
+        self.open_attempt = 0;
+        self.next_open_attempt = None;
+    }
+
+    /// Name of the IME server as passed to [`new`] or selected by [`new_with_fallback`].
     ///
-    /// [`set_forward_event_cb`]: ImeClient::set_forward_event_cb
-    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
-    /// [`set_preedit_draw_cb`]: ImeClient::set_preedit_draw_cb
-    pub fn process_event(&mut self, event: &xcb::Event) -> bool {
-        let raw = event.as_raw();
-        if !unsafe { xcb_xim_filter_event(self.im, raw as _) } {
-            let mask = unsafe { (*raw).response_type & !0x80 };
-            if (mask == XCB_KEY_PRESS) || (mask == XCB_KEY_RELEASE) {
-                match self.ic {
-                    Some(ic) => {
-                        unsafe {
-                            xcb_xim_forward_event(self.im, ic, raw as _);
-                        }
-                        return true;
-                    }
-                    _ => {
-                        self.try_open_ic();
-                    }
-                }
-            }
-        }
-        false
+    /// Return `None` if the default IME server is used.
+    ///
+    /// [`new`]: ImeClient::new
+    /// [`new_with_fallback`]: ImeClient::new_with_fallback
+    pub fn im_name(&self) -> Option<&str> {
+        self.im_name.as_ref().and_then(|name| name.to_str().ok())
     }
 
-    /// Set the position at which to place the IME window.
+    /// Screen the [`ImeClient`] was created for, as passed to [`new`].
     ///
-    /// Set the position of the IME window relative to the window specified by `win`. Coordinates
-    /// increase from the top left corner of the window.
+    /// [`new`]: ImeClient::new
+    pub fn screen_id(&self) -> i32 {
+        self.screen_id
+    }
+
+    /// Raw pointer to the XCB connection used by the [`ImeClient`].
     ///
-    /// Return `true` if an update for the IME window position has been sent to the IME, `false` if
-    /// the update has been queued. If there is still an update request queued and this method is
-    /// called, the previously queued request is discarded in favor of the new one.
-    pub fn update_pos(&mut self, win: Window, x: i16, y: i16) -> bool {
-        self.pos_req = ImePos {
-            win: win.resource_id(),
-            x,
-            y,
-        };
-        match self.ic {
-            Some(ic) => {
-                if self.is_processing_pos_update {
-                    self.pos_update_queued = true;
-                    return false;
-                }
-                self.send_pos_update(ic);
-                true
-            }
-            _ => {
-                self.try_open_ic();
-                false
-            }
+    /// The pointer is available regardless of the constructor, including [`unsafe_new`] and
+    /// [`from_borrowed`], which do not keep the [`xcb::Connection`] itself. It can be used to
+    /// integrate with event loops outside of this crate, e.g. to poll the file descriptor of the
+    /// connection or to flush it. The pointer must not be used after the connection has been
+    /// dropped.
+    ///
+    /// [`unsafe_new`]: ImeClient::unsafe_new
+    /// [`from_borrowed`]: ImeClient::from_borrowed
+    pub fn raw_connection(&self) -> *mut xcb::ffi::xcb_connection_t {
+        self.raw_conn as _
+    }
+
+    /// Encoding negotiated with the IME server.
+    ///
+    /// Return `None` if the IME has not been opened yet.
+    pub fn encoding(&self) -> Option<Encoding> {
+        if self.state != ImeState::Open {
+            return None;
         }
+        Encoding::from_raw(unsafe { xcb_xim_get_encoding(self.im) })
     }
 
-    fn send_pos_update(&mut self, ic: xcb_xic_t) {
-        self.is_processing_pos_update = true;
-        let spot = xcb_point_t {
-            x: self.pos_req.x,
-            y: self.pos_req.y,
-        };
-        let nested = unsafe {
-            xcb_xim_create_nested_list(
-                self.im,
-                XCB_XIM_XNSpotLocation,
-                &spot,
-                std::ptr::null_mut::<c_void>(),
-            )
-        };
-        if self.pos_req.win != self.pos_cur.win {
-            let w = &mut self.pos_req.win as *mut _;
-            unsafe {
-                xcb_xim_set_ic_values(
-                    self.im,
-                    ic,
-                    Some(update_pos_callback),
-                    self as *mut _ as _,
-                    XCB_XIM_XNClientWindow,
-                    w,
-                    XCB_XIM_XNFocusWindow,
-                    w,
-                    XCB_XIM_XNPreeditAttributes,
-                    &nested,
-                    std::ptr::null_mut::<c_void>(),
-                );
-            }
-        } else {
-            unsafe {
-                xcb_xim_set_ic_values(
-                    self.im,
-                    ic,
-                    Some(update_pos_callback),
-                    self as *mut _ as _,
-                    XCB_XIM_XNPreeditAttributes,
-                    &nested,
-                    std::ptr::null_mut::<c_void>(),
-                );
-            }
+    /// Encoding used for strings committed to `win`.
+    ///
+    /// XIM negotiates the encoding once per connection to an IME server, not per input context,
+    /// so this is the same as [`encoding`] for the window of the input context. Windows served by
+    /// different IME servers, e.g. through nested XIM proxies, need separate [`ImeClient`]s,
+    /// each with its own encoding.
+    ///
+    /// Return `None` if `win` has no input context or the IME has not been opened yet.
+    ///
+    /// [`encoding`]: ImeClient::encoding
+    pub fn encoding_for(&self, win: Window) -> Option<Encoding> {
+        if self.ic.is_none() || self.pos_cur.win != win.resource_id() {
+            return None;
         }
-        unsafe { free(nested.data as _) };
-        self.pos_cur = self.pos_req;
+        self.encoding()
     }
 
-    /// Set callback to be called once input composition is done.
+    /// Offer only `encoding` to the IME server.
     ///
-    /// The window (set by [`update_pos`]) as well as the completed input are passed as arguments.
+    /// By default UTF-8 as well as Compound Text is offered and the IME server chooses one of
+    /// them. Preferring [`Encoding::Utf8String`] keeps servers that support UTF-8 from sending
+    /// Compound Text, which would have to be converted. As the encoding is negotiated when
+    /// connecting to the IME server, the preference applies from the next connection on, i.e.
+    /// it should be set before the first key event or call to [`update_pos`]. XIM servers are
+    /// only required to support Compound Text, so consult [`encoding`] to check whether the
+    /// server honored the preference. Passing `None` offers both encodings again.
     ///
     /// [`update_pos`]: ImeClient::update_pos
-    pub fn set_commit_string_cb<F>(&mut self, f: F)
-    where
-        F: for<'a> FnMut(Window, &'a str) + 'static,
+    /// [`encoding`]: ImeClient::encoding
+    pub fn set_preferred_commit_encoding(&mut self, encoding: Option<Encoding>) {
+        self.use_compound_text = encoding != Some(Encoding::Utf8String);
+        self.use_utf8_string = encoding != Some(Encoding::CompoundText);
+        unsafe {
+            xcb_xim_set_use_compound_text(self.im, self.use_compound_text);
+            xcb_xim_set_use_utf8_string(self.im, self.use_utf8_string);
+        }
+    }
+
+    /// Check whether compound text conversion works.
+    ///
+    /// IME servers that do not support UTF-8 send strings as compound text, which xcb-imdkit
+    /// converts using the character set data of the system. If that data is missing, converted
+    /// strings are garbled. As the conversion cannot report this, a known string containing
+    /// non-ASCII characters is converted to compound text and back.
+    ///
+    /// Return `true` if the string survived the round trip.
+    pub fn compound_text_available(&self) -> bool {
+        const PROBE: &str = "a\u{e4}\u{436}";
+        unsafe {
+            let mut length = 0usize;
+            let ctext = xcb_utf8_to_compound_text(PROBE.as_ptr() as _, PROBE.len(), &mut length);
+            if ctext.is_null() {
+                return false;
+            }
+            let decoded = decode_xim_string(Some(Encoding::CompoundText), ctext, length);
+            free(ctext as _);
+            decoded == PROBE
+        }
+    }
+
+    /// Capabilities of the IME server.
+    ///
+    /// The XIM protocol does not announce which encodings an IME server supports, only the
+    /// encoding it selected out of the ones offered by the client is known. Extensions unknown
+    /// to xcb-imdkit are not listed. There is no extension for surrounding text, see the crate
+    /// level documentation.
+    ///
+    /// Return `None` if the IME has not been opened yet.
+    pub fn server_capabilities(&self) -> Option<ServerCapabilities> {
+        if self.state != ImeState::Open {
+            return None;
+        }
+        let extensions = XIM_EXTENSIONS
+            .iter()
+            .filter(|&&(_, minor)| unsafe {
+                xcb_xim_support_extension(self.im, XCB_XIM_EXTENSION as u16, minor as u16)
+            })
+            .map(|&(name, _)| name.to_owned())
+            .collect();
+        Some(ServerCapabilities {
+            encoding: self.encoding(),
+            extensions,
+        })
+    }
+
+    /// Check the health of the IME client, e.g. to attach the result to a bug report.
+    ///
+    /// The checks are performed on the current state without waiting for the IME server, so an
+    /// IME that has not been opened yet, e.g. because no key event or call to [`update_pos`]
+    /// happened so far, is reported as [`ImeState::Uninitialized`] without encoding and styles.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn self_test(&mut self) -> SelfTestReport {
+        SelfTestReport {
+            connection_alive: self.conn.as_ref().map(|conn| conn.has_error().is_ok()),
+            im_created: !self.im.is_null(),
+            compound_text: self.compound_text_available(),
+            state: self.state,
+            encoding: self.encoding(),
+            supported_styles: self.supported_styles.clone(),
+            active_style: self.active_style,
+        }
+    }
+
+    /// Input styles supported by the IME server.
+    ///
+    /// The supported styles are queried once the IME has been opened, `None` is returned until
+    /// the IME server replied.
+    pub fn supported_styles(&self) -> Option<&[InputStyle]> {
+        self.supported_styles.as_deref()
+    }
+
+    /// Counters of the events handled since the [`ImeClient`] was created or the counters were
+    /// reset with [`reset_stats`].
+    ///
+    /// [`reset_stats`]: ImeClient::reset_stats
+    pub fn stats(&self) -> ImeStats {
+        self.stats
+    }
+
+    /// Reset all counters returned by [`stats`] to zero.
+    ///
+    /// [`stats`]: ImeClient::stats
+    pub fn reset_stats(&mut self) {
+        self.stats = ImeStats::default();
+    }
+
+    /// Information about the current input context.
+    ///
+    /// Return `None` if no input context has been created yet.
+    pub fn ic_info(&self) -> Option<IcInfo> {
+        self.ic.map(|_| IcInfo {
+            window: Window::new(self.pos_cur.win),
+            focused: self.ic_focused,
+            input_style: self.active_style.unwrap_or(self.input_style),
+        })
+    }
+
+    /// Iterate over the open input contexts, e.g. for diagnostics.
+    ///
+    /// An [`ImeClient`] manages a single input context that is moved between windows by
+    /// [`update_pos`], so this yields at most the item returned by [`ic_info`]. The iterator
+    /// borrows the [`ImeClient`], so the input contexts cannot change during iteration.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    /// [`ic_info`]: ImeClient::ic_info
+    pub fn ics(&self) -> impl Iterator<Item = IcInfo> + '_ {
+        self.ic_info().into_iter()
+    }
+
+    /// Input style of the current input context.
+    ///
+    /// The IME server may grant a different style than the one requested in [`new`], e.g. the
+    /// preedit may be drawn by the IME instead of through the preedit callbacks. If the requested
+    /// style is not among the [`supported_styles`], the input context is created with the first
+    /// supported style of over-the-spot, preedit nothing and no preedit at all; each downgrade is
+    /// logged. The style is queried from the IME server after the input context has been created.
+    /// Until the IME server replied the style requested for the input context is returned.
+    ///
+    /// Return `None` if no input context has been created yet.
+    ///
+    /// [`new`]: ImeClient::new
+    /// [`supported_styles`]: ImeClient::supported_styles
+    pub fn active_style(&self) -> Option<InputStyle> {
+        self.active_style
+    }
+
+    /// Change the input style requested from the IME server.
+    ///
+    /// The XIM protocol allows to set the input style only when creating an input context, so an
+    /// existing input context is destroyed and a new one with `input_style` is created, which
+    /// discards an ongoing composition. Otherwise `input_style` is used once the input context is
+    /// created. The returned [`StyleChange`] tells which of both happened.
+    ///
+    /// Return [`ImeError::UnsupportedStyle`] without changing anything if the IME server is known
+    /// not to support `input_style`, see [`supported_styles`], and [`ImeError::CreateIcFailed`]
+    /// if the new input context could not be requested.
+    ///
+    /// [`supported_styles`]: ImeClient::supported_styles
+    pub fn set_input_style(&mut self, input_style: InputStyle) -> Result<StyleChange, ImeError> {
+        if let Some(styles) = &self.supported_styles {
+            if !styles.contains(&input_style) {
+                return Err(ImeError::UnsupportedStyle(input_style));
+            }
+        }
+        self.input_style = input_style;
+        let ic = match self.ic.take() {
+            Some(ic) => ic,
+            None => return Ok(StyleChange::Deferred),
+        };
+        self.active_style = None;
+        // replies for the old input context are not awaited anymore
+        self.is_processing_pos_update = false;
+        self.pos_update_queued = false;
+        unsafe {
+            xcb_xim_destroy_ic(self.im, ic, None, std::ptr::null_mut());
+        }
+        self.requests_pending = true;
+        let created = self.create_ic();
+        self.flush_if_enabled();
+        if created {
+            Ok(StyleChange::Recreated)
+        } else {
+            log("failed to request an input context with the new input style");
+            Err(ImeError::CreateIcFailed)
+        }
+    }
+
+    /// Register `preset` under `name` to be activated with [`activate_preset`].
+    ///
+    /// A preset registered before under the same name is replaced.
+    ///
+    /// [`activate_preset`]: ImeClient::activate_preset
+    pub fn register_style_preset(&mut self, name: &str, preset: StylePreset) {
+        self.style_presets.insert(name.to_owned(), preset);
+    }
+
+    /// Apply the input style and preedit attributes of the preset `name` to `win`.
+    ///
+    /// The attributes of the preset replace the ones set before, attributes that are `None` are
+    /// kept. If the input style differs from the current one, the input context is recreated as
+    /// described for [`set_input_style`] and receives the attributes on creation. Otherwise the
+    /// attributes are sent to the existing input context. `win` becomes the current window as with
+    /// [`update_pos`], keeping the current spot location.
+    ///
+    /// Return `false` without changing anything if no preset has been registered as `name` or
+    /// the IME server is known not to support its input style.
+    ///
+    /// [`set_input_style`]: ImeClient::set_input_style
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn activate_preset(&mut self, win: Window, name: &str) -> bool {
+        let preset = match self.style_presets.get(name) {
+            Some(preset) => preset.clone(),
+            None => return false,
+        };
+        if matches!(&self.supported_styles, Some(styles) if !styles.contains(&preset.style)) {
+            return false;
+        }
+        if let Some((foreground, background)) = preset.colors {
+            self.preedit_attrs.foreground = Some(foreground);
+            self.preedit_attrs.background = Some(background);
+        }
+        if preset.font_set.is_some() {
+            self.preedit_attrs.font_set = preset.font_set;
+        }
+        if preset.line_space.is_some() {
+            self.preedit_attrs.line_space = preset.line_space;
+        }
+        if preset.style != self.input_style {
+            // a failed creation is retried by `update_pos`
+            let _ = self.set_input_style(preset.style);
+        } else {
+            self.preedit_attrs_pending = true;
+        }
+        self.update_pos(win, self.pos_req.x, self.pos_req.y);
+        true
+    }
+
+    /// Switch between preedit drawn by the application and preedit drawn by the IME.
+    ///
+    /// If `enabled` is `true` the preedit style of the requested input style is replaced with
+    /// [`InputStyle::PREEDIT_CALLBACKS`], otherwise with [`InputStyle::PREEDIT_POSITION`], while
+    /// the status style is kept. The input context is recreated as described for
+    /// [`set_input_style`].
+    ///
+    /// Return `false` if [`set_input_style`] failed for the resulting style.
+    ///
+    /// [`set_input_style`]: ImeClient::set_input_style
+    #[cfg(feature = "preedit")]
+    pub fn set_preedit_callbacks_enabled(&mut self, enabled: bool) -> bool {
+        let preedit_styles = InputStyle::PREEDIT_CALLBACKS
+            | InputStyle::PREEDIT_AREA
+            | InputStyle::PREEDIT_POSITION
+            | InputStyle::PREEDIT_NOTHING
+            | InputStyle::PREEDIT_NONE;
+        let preedit = if enabled {
+            InputStyle::PREEDIT_CALLBACKS
+        } else {
+            InputStyle::PREEDIT_POSITION
+        };
+        let style = (self.input_style - preedit_styles) | preedit;
+        style == self.input_style || self.set_input_style(style).is_ok()
+    }
+
+    /// Collect the current configuration of the [`ImeClient`].
+    ///
+    /// This is meant for diagnostics, e.g. to include in bug reports.
+    pub fn config_snapshot(&self) -> ImeSnapshot {
+        ImeSnapshot {
+            im_name: self.im_name().map(str::to_owned),
+            screen_id: self.screen_id,
+            encoding: self.encoding(),
+            input_style: self.input_style,
+            supported_styles: self.supported_styles.clone(),
+            use_compound_text: self.use_compound_text,
+            use_utf8_string: self.use_utf8_string,
+            ic: self.ic_info(),
+        }
+    }
+
+    /// Force the encoding used to decode committed strings.
+    ///
+    /// This overrides the encoding negotiated with the IME server, which is useful to reproduce
+    /// encoding issues without a misbehaving IME server. Passing `None` restores the default
+    /// behavior of using the negotiated encoding.
+    #[cfg(feature = "debug-tools")]
+    pub fn debug_force_encoding(&mut self, encoding: Option<Encoding>) {
+        self.forced_encoding = encoding;
+    }
+
+    fn commit_encoding(&self, im: *mut xcb_xim_t) -> Option<Encoding> {
+        #[cfg(feature = "debug-tools")]
+        if let Some(encoding) = self.forced_encoding {
+            return Some(encoding);
+        }
+        Encoding::from_raw(unsafe { xcb_xim_get_encoding(im) })
+    }
+
+    /// Close the IME and release all resources.
+    ///
+    /// Dropping the [`ImeClient`] only destroys the input context, closes the connection to the
+    /// IME server and flushes the X connection. This additionally resets the input context and
+    /// removes its focus before doing so. Some IME servers otherwise keep displaying the preedit
+    /// of an ongoing composition until they notice the disconnect.
+    pub fn shutdown(mut self: Pin<Box<Self>>) {
+        self.close(true);
+    }
+
+    /// Destroy the input context and the IME.
+    ///
+    /// This may be called more than once, e.g. by [`shutdown`] and then by `drop`: `im` is reset
+    /// to null once destroyed and calls after that do nothing. Methods using `im` must not be
+    /// called once it is null, which holds as long as only consuming methods call `close`.
+    ///
+    /// [`shutdown`]: ImeClient::shutdown
+    fn close(&mut self, graceful: bool) {
+        if self.im.is_null() {
+            return;
+        }
+        unsafe {
+            if let Some(ic) = self.ic.take() {
+                if graceful {
+                    xcb_xim_reset_ic(self.im, ic, None, std::ptr::null_mut());
+                    xcb_xim_unset_ic_focus(self.im, ic);
+                }
+                xcb_xim_destroy_ic(self.im, ic, None, std::ptr::null_mut());
+            }
+            xcb_xim_close(self.im);
+            xcb_xim_destroy(self.im);
+            // make sure the requests freeing the input context reach the server right away; the
+            // connection is still alive as `conn` is only dropped after `drop` returned
+            xcb_flush(self.raw_conn);
+        }
+        self.im = std::ptr::null_mut();
+    }
+
+    fn handle_disconnect(&mut self) {
+        self.stats.disconnects += 1;
+        let had_ic = self.ic.take().is_some();
+        self.message_buffer = Vec::new();
+        self.active_style = None;
+        self.ic_pending = false;
+        self.ic_requested_at = None;
+        self.comm_window = None;
+        self.supported_styles = None;
+        self.unanswered_since = None;
+        #[cfg(feature = "preedit")]
+        {
+            self.preedit = PreeditString::default();
+        }
+        #[cfg(feature = "status")]
+        {
+            self.status_text = None;
+        }
+        self.set_state(ImeState::Disconnected);
+        if let Some(events) = self.events.as_mut() {
+            events.push(ImeEvent::Disconnected);
+        }
+        if had_ic {
+            self.notify_ic_destroyed();
+        }
+        // the buffered key would be lost otherwise, give it back as if the server forwarded it
+        #[cfg(feature = "forward")]
+        if let Some(event) = self
+            .replay_key
+            .take()
+            .and_then(|key| unsafe { key_event_from_ptr(&key) })
+        {
+            self.deliver_forwarded(event);
+        }
+    }
+
+    /// Give up on an input context the IME server did not create in time, see
+    /// [`set_ic_create_timeout`].
+    ///
+    /// [`set_ic_create_timeout`]: ImeClient::set_ic_create_timeout
+    fn check_ic_create_timeout(&mut self) {
+        let timed_out = matches!(
+            (self.ic_requested_at, self.ic_create_timeout),
+            (Some(requested), Some(timeout)) if requested.elapsed() >= timeout
+        );
+        if !timed_out {
+            return;
+        }
+        log("IME server did not create the input context in time, closing the connection");
+        unsafe {
+            xcb_xim_close(self.im);
+        }
+        self.requests_pending = true;
+        self.handle_disconnect();
+    }
+
+    fn open_im(&mut self) -> bool {
+        let data: *mut ImeClient = self as _;
+        let res = unsafe { xcb_xim_open(self.im, Some(open_callback), true, data as _) };
+        self.requests_pending |= res;
+        if res && self.state != ImeState::Open {
+            self.set_state(ImeState::Opening);
+        }
+        res
+    }
+
+    /// Lifecycle state of the connection to the IME server.
+    pub fn state(&self) -> ImeState {
+        self.state
+    }
+
+    /// Recover from IME servers that never create the requested input context.
+    ///
+    /// If the IME server has not created the input context `timeout` after it was requested, the
+    /// connection to the IME server is closed as if the server disconnected: the state changes to
+    /// [`ImeState::Disconnected`] and [`ImeEvent::Disconnected`] is queued. The IME is opened
+    /// again on the next key event or call to [`update_pos`]. The timeout is checked whenever
+    /// [`process_event`] is called. Passing `None`, the default, waits forever.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    /// [`process_event`]: ImeClient::process_event
+    pub fn set_ic_create_timeout(&mut self, timeout: Option<Duration>) {
+        self.ic_create_timeout = timeout;
+    }
+
+    /// Keep the key press that triggered the creation of the input context.
+    ///
+    /// Key events passed to [`process_event`] before an input context exists are not forwarded
+    /// to the IME server, so the first key typed after focusing a window is usually lost while
+    /// the input context is created. If enabled, the first key press arriving while the creation
+    /// is in progress is buffered instead, i.e. [`process_event`] returns `true`, and forwarded
+    /// once the input context has been created. If the connection is lost before, the key is
+    /// passed to the callback set with [`set_forward_event_cb`] as if the IME server did not use
+    /// it, which requires the `forward` feature. Further keys arriving in the meantime are not
+    /// buffered. Disabled by default.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`set_forward_event_cb`]: ImeClient::set_forward_event_cb
+    pub fn set_replay_first_key(&mut self, enable: bool) {
+        self.replay_first_key = enable;
+        if !enable {
+            self.replay_key = None;
+        }
+    }
+
+    /// Detect an IME server that stopped responding.
+    ///
+    /// If the IME server has not sent any message for `timeout` since a key event was forwarded
+    /// to it by [`process_event`], the callback set with [`set_server_stalled_cb`] is called. The
+    /// check runs whenever [`process_event`] is called, and a stall is reported only once until
+    /// the IME server responds again. Passing `None`, the default, disables the check.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`set_server_stalled_cb`]: ImeClient::set_server_stalled_cb
+    pub fn set_server_stall_timeout(&mut self, timeout: Option<Duration>) {
+        self.stall_timeout = timeout;
+        self.stall_reported = false;
+    }
+
+    /// Set callback called when the IME server stopped responding, see
+    /// [`set_server_stall_timeout`].
+    ///
+    /// The time since the first unanswered key event is supplied as argument. The application
+    /// can recover e.g. by dropping the [`ImeClient`] and creating a new one, which reconnects to
+    /// the IME server.
+    ///
+    /// [`set_server_stall_timeout`]: ImeClient::set_server_stall_timeout
+    pub fn set_server_stalled_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Duration) + 'static,
+    {
+        self.callbacks.server_stalled = Some(Box::new(f));
+    }
+
+    /// Remove the callback set with [`set_server_stalled_cb`], releasing the captured state.
+    ///
+    /// [`set_server_stalled_cb`]: ImeClient::set_server_stalled_cb
+    pub fn clear_server_stalled_cb(&mut self) {
+        self.callbacks.server_stalled = None;
+    }
+
+    /// Set callback called whenever the [`ImeState`] changes, see [`state`].
+    ///
+    /// [`state`]: ImeClient::state
+    pub fn set_state_changed_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(ImeState) + 'static,
+    {
+        self.callbacks.state_changed = Some(Box::new(f));
+    }
+
+    /// Remove the callback set with [`set_state_changed_cb`], releasing the captured state.
+    ///
+    /// [`set_state_changed_cb`]: ImeClient::set_state_changed_cb
+    pub fn clear_state_changed_cb(&mut self) {
+        self.callbacks.state_changed = None;
+    }
+
+    fn set_state(&mut self, state: ImeState) {
+        if self.state == state {
+            return;
+        }
+        self.state = state;
+        if let Some(events) = self.events.as_mut() {
+            events.push(ImeEvent::StateChanged { state });
+        } else if let Some(f) = self.callbacks.state_changed.as_mut() {
+            f(state);
+        }
+    }
+
+    /// Choose the input style for a new input context out of the styles supported by the IME
+    /// server, falling back to less demanding styles if the requested one is not supported.
+    fn select_input_style(&self) -> InputStyle {
+        let styles = match &self.supported_styles {
+            Some(styles) => styles,
+            None => return self.input_style,
+        };
+        let status_styles = InputStyle::STATUS_AREA
+            | InputStyle::STATUS_CALLBACKS
+            | InputStyle::STATUS_NOTHING
+            | InputStyle::STATUS_NONE;
+        let ladder = [
+            self.input_style,
+            InputStyle::PREEDIT_POSITION | (self.input_style & status_styles),
+            InputStyle::PREEDIT_POSITION | InputStyle::STATUS_NOTHING,
+            InputStyle::PREEDIT_NOTHING | InputStyle::STATUS_NOTHING,
+            InputStyle::PREEDIT_NOTHING | InputStyle::STATUS_NONE,
+            InputStyle::PREEDIT_NONE | InputStyle::STATUS_NONE,
+        ];
+        let mut previous = self.input_style;
+        for &style in ladder.iter() {
+            if styles.contains(&style) {
+                return style;
+            }
+            if style != previous {
+                log(&format!(
+                    "input style {} not supported by the IME server, trying {}",
+                    previous, style
+                ));
+                previous = style;
+            }
+        }
+        log(&format!(
+            "no fallback input style supported by the IME server, using {}",
+            self.input_style
+        ));
+        self.input_style
+    }
+
+    /// Request an input context for the current window, return whether the request was sent.
+    fn create_ic(&mut self) -> bool {
+        self.requests_pending = true;
+        self.ic_pending = true;
+        self.ic_requested_at = Some(Instant::now());
+        self.ic_style = self.select_input_style();
+        let input_style = self.ic_style.bits();
+        let spot = Point {
+            x: self.pos_req.x,
+            y: self.pos_req.y,
+        };
+        let mut list = NestedList::default();
+        list.push_attr(self.im, &IcAttr::Spot(spot));
+        if let Some(area) = self.area_req.take() {
+            list.push_attr(self.im, &IcAttr::Area(area.into()));
+            self.area_cur = Some(area);
+        }
+        self.preedit_attrs.push_to(self.im, &mut list);
+        let mut status_list = self.take_status_list();
+        let (status_name, status_nested) = status_attribute(&mut status_list);
+        let w = &mut self.pos_req.win as *mut u32;
+        let data: *mut ImeClient = self as _;
+        let sent = unsafe {
+            if list.is_empty() {
+                // an empty list is rejected by some IME servers
+                log("creating input context without preedit attributes");
+                xcb_xim_create_ic(
+                    self.im,
+                    Some(create_ic_callback),
+                    data as _,
+                    XCB_XIM_XNInputStyle,
+                    &input_style,
+                    XCB_XIM_XNClientWindow,
+                    w,
+                    XCB_XIM_XNFocusWindow,
+                    w,
+                    status_name,
+                    &status_nested,
+                    std::ptr::null_mut::<c_void>(),
+                )
+            } else {
+                let nested = list.as_raw();
+                xcb_xim_create_ic(
+                    self.im,
+                    Some(create_ic_callback),
+                    data as _,
+                    XCB_XIM_XNInputStyle,
+                    &input_style,
+                    XCB_XIM_XNClientWindow,
+                    w,
+                    XCB_XIM_XNFocusWindow,
+                    w,
+                    XCB_XIM_XNPreeditAttributes,
+                    &nested,
+                    status_name,
+                    &status_nested,
+                    std::ptr::null_mut::<c_void>(),
+                )
+            }
+        };
+        if !sent {
+            self.ic_pending = false;
+            self.ic_requested_at = None;
+            return false;
+        }
+        self.pos_cur = self.pos_req;
+        true
+    }
+
+    fn try_open_ic(&mut self) {
+        if self.ic.is_some() || self.ic_pending {
+            return;
+        }
+        if self.state == ImeState::Open {
+            self.create_ic();
+            return;
+        }
+        if let Some(next) = self.next_open_attempt {
+            if Instant::now() < next {
+                return;
+            }
+        }
+        if self.open_im() {
+            self.open_attempt = 0;
+            self.next_open_attempt = None;
+        } else if self.open_attempt < self.open_retries {
+            let delay = self
+                .open_retry_delay
+                .checked_mul(1u32.checked_shl(self.open_attempt).unwrap_or(u32::MAX))
+                .map_or(OPEN_RETRY_MAX_DELAY, |d| d.min(OPEN_RETRY_MAX_DELAY));
+            self.open_attempt += 1;
+            self.next_open_attempt = Some(Instant::now() + delay);
+        } else {
+            self.next_open_attempt = None;
+        }
+    }
+
+    /// Collect events in a queue instead of calling the callbacks.
+    ///
+    /// Once called, the callbacks set with the `set_*_cb` methods are no longer invoked. Instead
+    /// every event from the IME is stored as [`ImeEvent`] until it is retrieved with
+    /// [`drain_events`]. This is useful for applications that prefer to poll for events, e.g.
+    /// once per frame.
+    ///
+    /// [`drain_events`]: ImeClient::drain_events
+    pub fn use_event_queue(&mut self) {
+        if self.events.is_none() {
+            self.intern_message_atoms();
+            self.events = Some(Vec::new());
+        }
+    }
+
+    /// Hand every event from the IME to `f` instead of calling the callbacks.
+    ///
+    /// This is the same as [`use_event_queue`], except that the queued events are passed to `f`
+    /// by [`process_event`] right after the X event has been processed, so [`drain_events`] does
+    /// not need to be called. `f` runs on the thread calling [`process_event`], but the owned
+    /// [`ImeEvent`] can be sent on, e.g. through a channel to the thread owning the UI state.
+    ///
+    /// [`use_event_queue`]: ImeClient::use_event_queue
+    /// [`process_event`]: ImeClient::process_event
+    /// [`drain_events`]: ImeClient::drain_events
+    pub fn dispatch_with<F>(&mut self, f: F)
+    where
+        F: FnMut(ImeEvent) + 'static,
+    {
+        self.use_event_queue();
+        self.callbacks.dispatch = Some(Box::new(f));
+    }
+
+    /// Take all events queued since the last call.
+    ///
+    /// Return an empty [`Vec`] if [`use_event_queue`] has not been called.
+    ///
+    /// [`use_event_queue`]: ImeClient::use_event_queue
+    pub fn drain_events(&mut self) -> Vec<ImeEvent> {
+        self.events.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Let the IME client process XCB's events.
+    ///
+    /// Return `true` if the IME client is handling the event and `false` if the event is ignored
+    /// by the IME client and has to be handled separately.
+    ///
+    /// This method should be called on **any** event from the event queue and not just
+    /// keypress/keyrelease events as it handles other events as well.
+    ///
+    /// Typically you will want to let the IME client handle all keypress/keyrelease events in your
+    /// main loop. The IME client will then forward all key events that were not used for input
+    /// composition to the callback set by [`set_forward_event_cb`]. Often those events include all
+    /// keyrelease events as well as the events for `ESC`, `Enter` or key combinations such as
+    /// `CTRL+C`.
+    /// To obtain the text currently typed into the IME and the final string consult
+    /// [`set_preedit_draw_cb`] and [`set_commit_string_cb`].
+    ///
+    /// The XIM protocol does not acknowledge focus changes of the input context. Instead, the IME
+    /// server processes the requests of a client in the order they were sent. Key events are
+    /// forwarded in the same stream as the focus requests, so a key event passed to this method
+    /// after the input context has been focused, e.g. due to a call to [`update_pos`], is always
+    /// handled by the IME server with the new focus in effect. Key events arriving before an
+    /// input context exists are not forwarded at all.
+    ///
+    /// The XIM traffic flows through a communication window created by xcb-imdkit, see
+    /// [`comm_window`]. When routing events in a custom event loop, the events of that window
+    /// have to reach this method as well.
+    ///
+    /// [`set_forward_event_cb`]: ImeClient::set_forward_event_cb
+    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
+    /// [`set_preedit_draw_cb`]: ImeClient::set_preedit_draw_cb
+    /// [`update_pos`]: ImeClient::update_pos
+    /// [`comm_window`]: ImeClient::comm_window
+    pub fn process_event(&mut self, event: &xcb::Event) -> bool {
+        // XIM messages consumed by xcb-imdkit are left to the application as well
+        self.handle_event(event) == Filtered::Used
+    }
+
+    /// Process an event as [`process_event`] does, telling apart events consumed by xcb-imdkit.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    fn handle_event(&mut self, event: &xcb::Event) -> Filtered {
+        if self.stall_timeout.is_some() {
+            self.check_server_stall();
+        }
+        #[cfg(feature = "preedit")]
+        let stale_commit = self.pending_composition_commit.is_some();
+        let res = self.filter_event(event);
+        // the commit is not followed by a preedit update
+        #[cfg(feature = "preedit")]
+        if stale_commit {
+            self.flush_composition_commit();
+        }
+        if self.callbacks.dispatch.is_some() {
+            self.dispatch_events();
+        }
+        self.flush_if_enabled();
+        res
+    }
+
+    /// Window through which the XIM messages of the IME server are received.
+    ///
+    /// xcb-imdkit creates this window itself when connecting to the IME server and does not allow
+    /// to specify a different one. Its id is not exposed by the C API either, so it is learned from
+    /// the first `ClientMessage` event [`process_event`] hands to xcb-imdkit. Return `None` until
+    /// the IME server has sent a message or after the connection to the IME server was lost.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    pub fn comm_window(&self) -> Option<Window> {
+        self.comm_window
+    }
+
+    fn dispatch_events(&mut self) {
+        let events = self.drain_events();
+        if let Some(f) = self.callbacks.dispatch.as_mut() {
+            for event in events {
+                f(event);
+            }
+        }
+    }
+
+    fn filter_event(&mut self, event: &xcb::Event) -> Filtered {
+        if self.ic_requested_at.is_some() {
+            self.check_ic_create_timeout();
+        }
+        if matches!(self.next_open_attempt, Some(next) if Instant::now() >= next) {
+            self.try_open_ic();
+        }
+        match event {
+            xcb::Event::X(xcb::x::Event::ReparentNotify(event)) => {
+                self.notify_reparented(event.window());
+            }
+            xcb::Event::X(xcb::x::Event::ConfigureNotify(event)) if self.rehome_on_configure => {
+                self.notify_reparented(event.window());
+            }
+            xcb::Event::X(xcb::x::Event::MappingNotify(event))
+                if event.request() == xcb::x::Mapping::Keyboard =>
+            {
+                self.invalidate_key_symbols();
+            }
+            xcb::Event::Xkb(xcb::xkb::Event::NewKeyboardNotify(_))
+            | xcb::Event::Xkb(xcb::xkb::Event::MapNotify(_)) => {
+                self.invalidate_key_symbols();
+            }
+            _ => {}
+        }
+        if self.callbacks.message_tap.is_some()
+            || self.callbacks.ic_destroyed.is_some()
+            || self.events.is_some()
+        {
+            self.tap_message(event);
+        }
+        if self.is_passthrough_key(event)
+            || self.is_disabled_window_key(event)
+            || (self.ignore_synthetic_keys && is_synthetic(event))
+        {
+            return Filtered::Ignored;
+        }
+        let raw = event.as_raw();
+        if unsafe { xcb_xim_filter_event(self.im, raw as _) } {
+            // replies and requests of the callbacks may have been sent
+            self.requests_pending = true;
+            self.stats.filtered_events += 1;
+            self.unanswered_since = None;
+            self.stall_reported = false;
+            if let xcb::Event::X(xcb::x::Event::ClientMessage(event)) = event {
+                self.comm_window = Some(event.window());
+            }
+            return Filtered::Consumed;
+        } else {
+            let mask = unsafe { (*raw).response_type & !0x80 };
+            if (mask == XCB_KEY_PRESS) || (mask == XCB_KEY_RELEASE) {
+                let event_mask = if mask == XCB_KEY_PRESS {
+                    xcb::x::EventMask::KEY_PRESS
+                } else {
+                    xcb::x::EventMask::KEY_RELEASE
+                };
+                match self.ic {
+                    Some(_) if !self.forwards(event_mask) => {}
+                    Some(ic) => {
+                        unsafe {
+                            xcb_xim_forward_event(self.im, ic, raw as _);
+                        }
+                        self.requests_pending = true;
+                        self.stats.forwarded += 1;
+                        #[cfg(feature = "forward")]
+                        {
+                            self.last_forwarded = KeyToken::of(event);
+                        }
+                        self.unanswered_since.get_or_insert_with(Instant::now);
+                        return Filtered::Used;
+                    }
+                    _ => {
+                        self.try_open_ic();
+                        #[cfg(feature = "compose")]
+                        if mask == XCB_KEY_PRESS && self.compose(event) {
+                            return Filtered::Used;
+                        }
+                        if mask == XCB_KEY_PRESS
+                            && self.replay_first_key
+                            && self.ic_pending
+                            && self.replay_key.is_none()
+                        {
+                            self.replay_key =
+                                Some(unsafe { *(raw as *const xcb_key_press_event_t) });
+                            return Filtered::Used;
+                        }
+                    }
+                }
+            }
+        }
+        Filtered::Ignored
+    }
+
+    /// Report a stalled IME server once per stall, see [`set_server_stall_timeout`].
+    ///
+    /// [`set_server_stall_timeout`]: ImeClient::set_server_stall_timeout
+    fn check_server_stall(&mut self) {
+        let elapsed = match (self.unanswered_since, self.stall_timeout) {
+            (Some(since), Some(timeout)) if !self.stall_reported && since.elapsed() >= timeout => {
+                since.elapsed()
+            }
+            _ => return,
+        };
+        self.stall_reported = true;
+        log(&format!(
+            "IME server has not responded for {} ms",
+            elapsed.as_millis()
+        ));
+        if let Some(events) = self.events.as_mut() {
+            events.push(ImeEvent::ServerStalled { elapsed });
+        } else if let Some(f) = self.callbacks.server_stalled.as_mut() {
+            f(elapsed);
+        }
+    }
+
+    /// Set a table for client-side composition while no IME server is available.
+    ///
+    /// As long as no input context exists, key press events passed to [`process_event`] are
+    /// matched against the compose sequences of `table`. Keys that are part of a sequence are
+    /// consumed, i.e. [`process_event`] returns `true`, and completed sequences are delivered like
+    /// strings committed by the IME. This provides basic Compose key and dead key support without
+    /// an IME server. The keyboard mapping is queried from the X server, which requires the
+    /// [`ImeClient`] to be created with [`new`]. Passing `None` disables the composition.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`new`]: ImeClient::new
+    #[cfg(feature = "compose")]
+    pub fn set_compose_table(&mut self, table: Option<ComposeTable>) {
+        self.compose_table = table;
+        self.compose_pending.clear();
+    }
+
+    #[cfg(feature = "compose")]
+    fn compose(&mut self, event: &xcb::Event) -> bool {
+        let event = match event {
+            xcb::Event::X(xcb::x::Event::KeyPress(event)) => event,
+            _ => return false,
+        };
+        if self.compose_table.is_none() {
+            return false;
+        }
+        let keysym = self.keysym_for(event.detail(), event.state().bits() as u16);
+        let table = match self.compose_table.as_ref() {
+            Some(table) => table,
+            None => return false,
+        };
+        match table.feed(&mut self.compose_pending, keysym) {
+            compose::ComposeResult::Ignored => false,
+            compose::ComposeResult::Consumed => true,
+            compose::ComposeResult::Composed(text) => {
+                self.deliver_commit(event.event(), &text);
+                true
+            }
+        }
+    }
+
+    /// Drop strings committed twice in a row, e.g. due to races when reconnecting to the IME.
+    ///
+    /// If enabled, a string is dropped and a warning is logged if the same string has been
+    /// committed to the same window less than 20 ms earlier. Typing the same character twice by
+    /// hand takes longer than that, but a single key event producing the same string twice, e.g.
+    /// `aa` sent as two commits, or fast automated input may be dropped as well. Duplicates
+    /// arriving later or separated by a different commit are not detected. Disabled by default.
+    pub fn set_dedup_commits(&mut self, enable: bool) {
+        self.dedup_commits = enable;
+        self.last_commit = None;
+    }
+
+    /// Return `true` if `text` repeats the last commit to `win`, see [`set_dedup_commits`].
+    ///
+    /// [`set_dedup_commits`]: ImeClient::set_dedup_commits
+    fn is_duplicate_commit(&mut self, win: Window, text: &str) -> bool {
+        if !self.dedup_commits {
+            return false;
+        }
+        let now = Instant::now();
+        let duplicate = matches!(&self.last_commit,
+            Some((last_win, last_text, at))
+                if *last_win == win && last_text == text && now - *at < COMMIT_DEDUP_WINDOW);
+        self.last_commit = Some((win, text.to_owned(), now));
+        duplicate
+    }
+
+    fn deliver_commit(&mut self, win: Window, text: &str) {
+        if self.grab_suspended {
+            self.grab_commits.push((win, text.to_owned()));
+            return;
+        }
+        if self.is_duplicate_commit(win, text) {
+            log(&format!(
+                "dropping duplicate commit of {} bytes",
+                text.len()
+            ));
+            return;
+        }
+        self.stats.commits += 1;
+        self.remember_commit(win, text);
+        if self.waiting_for_commit && self.awaited_commit.is_none() {
+            self.awaited_commit = Some((win, text.to_owned()));
+        } else if let Some(events) = self.events.as_mut() {
+            events.push(ImeEvent::Commit {
+                win,
+                text: text.to_owned(),
+            });
+        } else {
+            #[cfg(feature = "preedit")]
+            if self.callbacks.composition.is_some() {
+                self.flush_composition_commit();
+                self.pending_composition_commit = Some((win, text.to_owned()));
+            }
+            if let Some(f) = self.callbacks.commit_string.as_mut() {
+                f(win, text);
+            }
+            if let Some(f) = self.callbacks.commit_utf16.as_mut() {
+                f(win, text.encode_utf16().collect());
+            }
+            if let Some(f) = self.callbacks.commit.as_mut() {
+                let commit = CommitString {
+                    text: text.to_owned(),
+                    kind: CommitKind::of(text),
+                    byte_len_hint: text.len(),
+                };
+                f(win, commit);
+            }
+        }
+    }
+
+    fn remember_commit(&mut self, win: Window, text: &str) {
+        let win = win.resource_id();
+        self.last_commits.retain(|(w, _)| *w != win);
+        if text.len() > LAST_COMMIT_MAX_BYTES {
+            return;
+        }
+        if self.last_commits.len() == LAST_COMMIT_MAX_WINDOWS {
+            self.last_commits.pop();
+        }
+        self.last_commits.insert(0, (win, text.to_owned()));
+    }
+
+    /// Last string committed by the IME to `win`.
+    ///
+    /// The last commits of the 16 windows committed to most recently are retained. Commits of
+    /// more than 4 KiB are not retained, `None` is returned after such a commit. The commit
+    /// callbacks, e.g. the one set with [`set_commit_string_cb`], signal when this changes.
+    ///
+    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
+    pub fn last_commit(&self, win: Window) -> Option<&str> {
+        self.last_commits
+            .iter()
+            .find(|(w, _)| *w == win.resource_id())
+            .map(|(_, text)| text.as_str())
+    }
+
+    #[cfg(feature = "preedit")]
+    fn flush_composition_commit(&mut self) {
+        if let Some((win, text)) = self.pending_composition_commit.take() {
+            if let Some(f) = self.callbacks.composition.as_mut() {
+                let update = CompositionUpdate {
+                    committed: Some(text),
+                    preedit: None,
+                };
+                f(win, update);
+            }
+        }
+    }
+
+    /// Set a callback that observes the XIM protocol messages received from the IME server.
+    ///
+    /// This is meant for debugging interoperability issues with IME servers. The callback is
+    /// called by [`process_event`] with the major opcode and the bytes of every complete message
+    /// sent through `ClientMessage` events, including the message header. Messages sent by the
+    /// client and messages the IME server transfers through window properties are not observed,
+    /// as xcb-imdkit offers no way to intercept them. Observing messages requires the
+    /// [`ImeClient`] to be created with [`new`] and slows down event processing.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`new`]: ImeClient::new
+    pub fn set_message_tap<F>(&mut self, f: F)
+    where
+        F: for<'a> FnMut(u8, &'a [u8]) + 'static,
+    {
+        self.intern_message_atoms();
+        self.callbacks.message_tap = Some(Box::new(f));
+    }
+
+    fn intern_message_atoms(&mut self) {
+        if self.message_atoms.is_some() {
+            return;
+        }
+        self.message_atoms = self.conn.as_deref().and_then(|conn| {
+            let intern = |name: &[u8]| {
+                conn.send_request(&xcb::x::InternAtom {
+                    only_if_exists: false,
+                    name,
+                })
+            };
+            let protocol = intern(b"_XIM_PROTOCOL");
+            let more_data = intern(b"_XIM_MOREDATA");
+            Some((
+                conn.wait_for_reply(protocol).ok()?.atom(),
+                conn.wait_for_reply(more_data).ok()?.atom(),
+            ))
+        });
+    }
+
+    /// Set callback called when the input context has been destroyed on the side of the IME
+    /// server.
+    ///
+    /// XIM has no notification for this, so two signs are used:
+    ///
+    /// - The IME server went away, e.g. because its window has been destroyed when it crashed or
+    ///   restarted. The callback is called in addition to the handling of the lost connection,
+    ///   see [`ImeState::Disconnected`].
+    /// - The IME server answered a request with an `XIM_ERROR` naming the input context with the
+    ///   error code `BadProtocol` or `BadSomething`. The protocol has no error code for unknown
+    ///   input contexts, so this is a heuristic: errors about attribute values, e.g.
+    ///   `BadSpotLocation`, do not count. The input context is dropped without closing the
+    ///   connection. Detecting the error requires the [`ImeClient`] to be created with [`new`].
+    ///
+    /// The callback is called with the current window (set by [`update_pos`]). A new input
+    /// context is created by the next call to [`update_pos`] or key event passed to
+    /// [`process_event`].
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    /// [`process_event`]: ImeClient::process_event
+    /// [`new`]: ImeClient::new
+    pub fn set_ic_destroyed_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window) + 'static,
+    {
+        self.intern_message_atoms();
+        self.callbacks.ic_destroyed = Some(Box::new(f));
+    }
+
+    /// Remove the callback set with [`set_ic_destroyed_cb`], releasing the captured state.
+    ///
+    /// [`set_ic_destroyed_cb`]: ImeClient::set_ic_destroyed_cb
+    pub fn clear_ic_destroyed_cb(&mut self) {
+        self.callbacks.ic_destroyed = None;
+    }
+
+    /// Forget the input context if the `XIM_ERROR` `message` indicates that the IME server does
+    /// not know it, see [`set_ic_destroyed_cb`].
+    ///
+    /// [`set_ic_destroyed_cb`]: ImeClient::set_ic_destroyed_cb
+    fn handle_xim_error(&mut self, message: &[u8]) {
+        // the header is followed by CARD16 input-method-ID, CARD16 input-context-ID,
+        // BITMASK16 flag and CARD16 error code
+        let field = |offset: usize| {
+            message
+                .get(offset..offset + 2)
+                .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+        };
+        let (ic, flag, code) = match (field(6), field(8), field(10)) {
+            (Some(ic), Some(flag), Some(code)) => (ic, flag, code),
+            _ => return,
+        };
+        if flag & XIM_ERROR_IC_VALID == 0
+            || self.ic != Some(ic)
+            || !matches!(code, XIM_BAD_PROTOCOL | XIM_BAD_SOMETHING)
+        {
+            return;
+        }
+        log(&format!(
+            "IME server reported error {} for the input context, dropping it",
+            code
+        ));
+        self.ic = None;
+        self.notify_ic_destroyed();
+    }
+
+    /// Reset the state of the input context destroyed on the side of the IME server and report
+    /// it.
+    fn notify_ic_destroyed(&mut self) {
+        self.active_style = None;
+        self.is_processing_pos_update = false;
+        self.pos_update_queued = false;
+        #[cfg(feature = "preedit")]
+        {
+            self.preedit = PreeditString::default();
+        }
+        let win = Window::new(self.pos_cur.win);
+        // makes the next `update_pos` send the windows to the new input context
+        self.pos_cur.win = 0;
+        if let Some(events) = self.events.as_mut() {
+            events.push(ImeEvent::IcDestroyed { win });
+        } else if let Some(f) = self.callbacks.ic_destroyed.as_mut() {
+            f(win);
+        }
+    }
+
+    /// Remove the callback set with [`set_message_tap`], releasing the captured state.
+    ///
+    /// [`set_message_tap`]: ImeClient::set_message_tap
+    pub fn clear_message_tap(&mut self) {
+        self.callbacks.message_tap = None;
+    }
+
+    fn tap_message(&mut self, event: &xcb::Event) {
+        let (protocol, more_data) = match self.message_atoms {
+            Some(atoms) => atoms,
+            None => return,
+        };
+        let (kind, data) = match event {
+            xcb::Event::X(xcb::x::Event::ClientMessage(event)) => match event.data() {
+                xcb::x::ClientMessageData::Data8(data) => (event.r#type(), data),
+                _ => return,
+            },
+            _ => return,
+        };
+        // messages longer than one event are split into _XIM_MOREDATA fragments followed by a
+        // final _XIM_PROTOCOL fragment
+        if kind == more_data {
+            if self.message_buffer.len() + data.len() > MAX_XIM_MESSAGE_BYTES {
+                log("dropping XIM message exceeding the maximum length");
+                self.message_buffer = Vec::new();
+            } else {
+                self.message_buffer.extend_from_slice(&data);
+            }
+            return;
+        }
+        if kind != protocol {
+            return;
+        }
+        let mut message = std::mem::take(&mut self.message_buffer);
+        message.extend_from_slice(&data);
+        // the header is CARD8 major opcode, CARD8 minor opcode, CARD16 length in 4 byte units
+        let length = 4 + 4 * u16::from_ne_bytes([message[2], message[3]]) as usize;
+        message.truncate(length);
+        if message[0] as u32 == XCB_XIM_ERROR {
+            self.handle_xim_error(&message);
+        }
+        if let Some(f) = self.callbacks.message_tap.as_mut() {
+            f(message[0], &message);
+        }
+    }
+
+    /// Set keys that are never passed to the IME.
+    ///
+    /// Each key is given as pair of keysym and modifier mask (as in the `state` of key events).
+    /// [`process_event`] returns `false` for key events matching one of these keys without
+    /// letting the IME see them, which is useful for global hotkeys that should not be
+    /// intercepted by the IME. The keysym is resolved including the Shift level, the Caps Lock and
+    /// Num Lock modifiers are ignored.
+    ///
+    /// The keyboard mapping is queried from the X server, which requires the [`ImeClient`] to be
+    /// created with [`new`].
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`new`]: ImeClient::new
+    pub fn set_passthrough_keys(&mut self, keys: &[(u32, u16)]) {
+        self.passthrough_keys = keys
+            .iter()
+            .map(|&(keysym, modifiers)| (keysym, modifiers & PASSTHROUGH_MODIFIER_MASK))
+            .collect();
+    }
+
+    /// Enable or disable the IME for `win`.
+    ///
+    /// Key events of disabled windows are not passed to the IME, [`process_event`] returns
+    /// `false` for them, e.g. for password fields. Disabling the current window (set by
+    /// [`update_pos`]) resets the input context, discarding an ongoing composition. All windows
+    /// are enabled by default.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn set_window_enabled(&mut self, win: Window, enabled: bool) {
+        let win = win.resource_id();
+        if enabled {
+            self.disabled_windows.remove(&win);
+            return;
+        }
+        if self.disabled_windows.insert(win) && win == self.pos_cur.win {
+            if let Some(ic) = self.ic {
+                unsafe {
+                    xcb_xim_reset_ic(self.im, ic, None, std::ptr::null_mut());
+                }
+                self.requests_pending = true;
+                self.flush_if_enabled();
+            }
+        }
+    }
+
+    /// Forward key releases to the IME.
+    ///
+    /// If disabled, [`process_event`] passes only key press events to the IME and returns `false`
+    /// for key release events, so the application handles them directly without the round trip
+    /// to the IME server. This works around IME servers that handle releases twice, causing stuck
+    /// keys, and reduces the latency of releases. However, IME servers that act on releases no
+    /// longer see them, e.g. toggling the input method by tapping `Shift` stops working and
+    /// composition relying on released modifiers can misbehave. Enabled by default.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    pub fn set_forward_releases(&mut self, enable: bool) {
+        self.forward_releases = enable;
+    }
+
+    /// Return whether key events of the type `event_mask` are forwarded to the IME.
+    fn forwards(&self, event_mask: xcb::x::EventMask) -> bool {
+        if event_mask == xcb::x::EventMask::KEY_RELEASE && !self.forward_releases {
+            return false;
+        }
+        self.forward_event_mask & event_mask.bits() != 0
+    }
+
+    /// Return whether [`process_event`] would pass the key event `event` to the IME.
+    ///
+    /// This allows to give the IME precedence over accelerators of the application: events for
+    /// which `true` is returned should be passed to [`process_event`] before being matched
+    /// against accelerators, e.g. to let the IME use `Ctrl` combinations for candidate selection.
+    /// The decision is the same as within [`process_event`]: keys of disabled windows,
+    /// passthrough keys, synthetic keys if ignored and key events the IME server did not select
+    /// with `XIM_SET_EVENT_MASK` are not claimed. Without an input context, only the key that
+    /// would be buffered by [`set_replay_first_key`] is claimed, client-side composition is not
+    /// considered.
+    ///
+    /// Nothing is sent to the IME server, the only side effect is that the keyboard mapping is
+    /// queried once if passthrough keys are set. The IME server decides whether it uses a key
+    /// only after receiving it, so claimed keys may still be passed back to the callback set with
+    /// [`set_forward_event_cb`].
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`set_replay_first_key`]: ImeClient::set_replay_first_key
+    /// [`set_forward_event_cb`]: ImeClient::set_forward_event_cb
+    pub fn claims_key(&mut self, event: &xcb::Event) -> bool {
+        let event_mask = match event {
+            xcb::Event::X(xcb::x::Event::KeyPress(_)) => xcb::x::EventMask::KEY_PRESS,
+            xcb::Event::X(xcb::x::Event::KeyRelease(_)) => xcb::x::EventMask::KEY_RELEASE,
+            _ => return false,
+        };
+        if self.is_passthrough_key(event)
+            || self.is_disabled_window_key(event)
+            || (self.ignore_synthetic_keys && is_synthetic(event))
+        {
+            return false;
+        }
+        match self.ic {
+            Some(_) => self.forwards(event_mask),
+            None => {
+                event_mask == xcb::x::EventMask::KEY_PRESS
+                    && self.replay_first_key
+                    && self.ic_pending
+                    && self.replay_key.is_none()
+            }
+        }
+    }
+
+    /// Do not pass synthetic key events to the IME.
+    ///
+    /// Synthetic events are sent by other clients with `SendEvent` and are marked as such by the
+    /// X server. If enabled, [`process_event`] returns `false` for synthetic key events without
+    /// letting the IME see them, so e.g. a password field can refuse injected composition. Events
+    /// generated with the XTEST extension are indistinguishable from real input and are not
+    /// affected. Disabled by default.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    pub fn set_ignore_synthetic_keys(&mut self, ignore: bool) {
+        self.ignore_synthetic_keys = ignore;
+    }
+
+    fn is_disabled_window_key(&self, event: &xcb::Event) -> bool {
+        match event {
+            xcb::Event::X(xcb::x::Event::KeyPress(event))
+            | xcb::Event::X(xcb::x::Event::KeyRelease(event)) => {
+                self.disabled_windows.contains(&event.event().resource_id())
+            }
+            _ => false,
+        }
+    }
+
+    fn is_passthrough_key(&mut self, event: &xcb::Event) -> bool {
+        if self.passthrough_keys.is_empty() {
+            return false;
+        }
+        let (detail, state) = match event {
+            xcb::Event::X(xcb::x::Event::KeyPress(event))
+            | xcb::Event::X(xcb::x::Event::KeyRelease(event)) => {
+                (event.detail(), event.state().bits() as u16)
+            }
+            _ => return false,
+        };
+        let keysym = self.keysym_for(detail, state);
+        self.passthrough_keys
+            .contains(&(keysym, state & PASSTHROUGH_MODIFIER_MASK))
+    }
+
+    /// Resolve the keysym of a key event given its keycode (`detail`) and modifier `state`.
+    ///
+    /// The keyboard mapping is queried from the X server on first use and cached. The cache is
+    /// dropped when [`process_event`] sees a `MappingNotify` for the keyboard or an XKB
+    /// `NewKeyboardNotify`/`MapNotify` event, so layout changes are picked up. Querying the mapping
+    /// requires the [`ImeClient`] to be created with [`new`].
+    ///
+    /// Return `0` (`NoSymbol`) if the keycode has no keysym or the mapping is not available.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`new`]: ImeClient::new
+    pub fn keysym_for(&mut self, detail: u8, state: u16) -> u32 {
+        if self.key_symbols.is_none() {
+            self.key_symbols = self.conn.as_deref().and_then(KeySymbols::query);
+        }
+        self.key_symbols
+            .as_ref()
+            .map_or(0, |key_symbols| key_symbols.keysym(detail, state))
+    }
+
+    fn invalidate_key_symbols(&mut self) {
+        self.key_symbols = None;
+        // keysyms of a pending compose sequence were resolved with the old mapping
+        #[cfg(feature = "compose")]
+        self.compose_pending.clear();
+    }
+
+    /// X event mask of the key events that are forwarded to the IME.
+    ///
+    /// The IME server may restrict which events it wants to receive; key events not covered by
+    /// this mask are not forwarded by [`process_event`] and have to be handled by the
+    /// application. Until the server sends its preference, key press and key release events are
+    /// forwarded.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    pub fn forwarded_event_mask(&self) -> u32 {
+        self.forward_event_mask
+    }
+
+    /// Set the position at which to place the IME window.
+    ///
+    /// Set the position of the IME window relative to the window specified by `win`. Coordinates
+    /// increase from the top left corner of the window.
+    ///
+    /// Return `true` if an update for the IME window position has been sent to the IME, `false` if
+    /// the update has been queued. If there is still an update request queued and this method is
+    /// called, the previously queued request is discarded in favor of the new one.
+    pub fn update_pos(&mut self, win: Window, x: i16, y: i16) -> bool {
+        self.pos_req = ImePos {
+            win: win.resource_id(),
+            x,
+            y,
+        };
+        self.request_pos_update()
+    }
+
+    /// Set the position at which to place the IME window next to a glyph cluster.
+    ///
+    /// XIM only knows pixel positions, so the spot is placed at the trailing edge of the cluster
+    /// on its baseline, see [`ClusterSpot::spot`]. For right-to-left scripts this is the left
+    /// edge of the cluster, which keeps the preedit from covering the text before the caret.
+    ///
+    /// Return value is the same as for [`update_pos`].
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn update_pos_cluster(&mut self, win: Window, cluster: ClusterSpot) -> bool {
+        let spot = cluster.spot();
+        self.update_pos(win, spot.x, spot.y)
+    }
+
+    /// Set the position of the IME window and the status area in one update.
+    ///
+    /// The spot location is sent as preedit attribute and `status_area` as status attribute, both
+    /// within the same request to the IME server. XIM encodes each group of attributes as its own
+    /// nested list, so no separator is needed between them. `status_area` is relative to `win`
+    /// and only used by IME servers with [`InputStyle::STATUS_AREA`].
+    ///
+    /// Return value is the same as for [`update_pos`].
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn update_all<R>(&mut self, win: Window, x: i16, y: i16, status_area: R) -> bool
+    where
+        R: Into<Rectangle>,
+    {
+        self.status_area_req = Some(status_area.into().into());
+        self.update_pos(win, x, y)
+    }
+
+    /// Set the position of the status area of the IME within `win`.
+    ///
+    /// The size of the status area is kept, see [`update_status_size`]. Only IME servers with
+    /// [`InputStyle::STATUS_AREA`] use the status area.
+    ///
+    /// Return value is the same as for [`update_pos`], `false` is also returned if `win` is not
+    /// the current window.
+    ///
+    /// [`update_status_size`]: ImeClient::update_status_size
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn update_status_position(&mut self, win: Window, x: i16, y: i16) -> bool {
+        self.update_status_area(win, |area| {
+            area.x = x;
+            area.y = y;
+        })
+    }
+
+    /// Set the size of the status area of the IME within `win`.
+    ///
+    /// The position of the status area is kept, see [`update_status_position`].
+    ///
+    /// Return value is the same as for [`update_status_position`].
+    ///
+    /// [`update_status_position`]: ImeClient::update_status_position
+    pub fn update_status_size(&mut self, win: Window, width: u16, height: u16) -> bool {
+        self.update_status_area(win, |area| {
+            area.width = width;
+            area.height = height;
+        })
+    }
+
+    fn update_status_area<F>(&mut self, win: Window, f: F) -> bool
+    where
+        F: FnOnce(&mut Rectangle),
+    {
+        if win.resource_id() != self.pos_req.win {
+            return false;
+        }
+        let mut area = self
+            .status_area_req
+            .or(self.status_area_cur)
+            .map_or_else(Rectangle::default, Rectangle::from);
+        f(&mut area);
+        self.status_area_req = Some(area.into());
+        self.request_pos_update()
+    }
+
+    /// Move the input context to `win` and set whether it has the input focus.
+    ///
+    /// The position within the window is kept. The focus change is sent to the IME server after
+    /// the window change, if an earlier update of the input context is still in progress only
+    /// once the window change has been sent. The IME server processes the requests of a client
+    /// in order, so key events forwarded afterwards are handled with both changes in effect.
+    /// Input contexts are focused when created unless this has been called with `focused` set
+    /// to `false` before.
+    ///
+    /// Return value is the same as for [`update_pos`].
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn set_focus_window(&mut self, win: Window, focused: bool) -> bool {
+        self.pos_req.win = win.resource_id();
+        self.focus_req = focused;
+        let res = self.request_pos_update();
+        // otherwise `update_pos_callback` sends the focus change after the queued window change
+        if !self.pos_update_queued {
+            self.sync_focus();
+        }
+        self.flush_if_enabled();
+        res
+    }
+
+    /// Send the focus change requested with [`set_focus_window`] unless it is in effect already.
+    ///
+    /// [`set_focus_window`]: ImeClient::set_focus_window
+    fn sync_focus(&mut self) {
+        // the focus is restored by `end_grab`
+        let focused = self.focus_req && !self.grab_suspended;
+        if let Some(ic) = self.ic.filter(|_| self.ic_focused != focused) {
+            unsafe {
+                if focused {
+                    xcb_xim_set_ic_focus(self.im, ic);
+                } else {
+                    xcb_xim_unset_ic_focus(self.im, ic);
+                }
+            }
+            self.requests_pending = true;
+            self.ic_focused = focused;
+        }
+    }
+
+    /// Suspend the IME while the application grabs the pointer, see [`begin_grab`].
+    ///
+    /// Disabled by default, in which case [`begin_grab`] and [`end_grab`] do nothing.
+    ///
+    /// [`begin_grab`]: ImeClient::begin_grab
+    /// [`end_grab`]: ImeClient::end_grab
+    pub fn set_suspend_during_grab(&mut self, enable: bool) {
+        self.suspend_during_grab = enable;
+        if !enable {
+            self.end_grab();
+        }
+    }
+
+    /// Inform the IME client that the application started a pointer grab, e.g. for a drag
+    /// operation.
+    ///
+    /// If enabled with [`set_suspend_during_grab`], the input context loses the focus until
+    /// [`end_grab`] is called, which hides the preedit and status windows drawn by the IME
+    /// server without discarding an ongoing composition. Strings committed in the meantime are
+    /// kept and delivered by [`end_grab`].
+    ///
+    /// [`set_suspend_during_grab`]: ImeClient::set_suspend_during_grab
+    /// [`end_grab`]: ImeClient::end_grab
+    pub fn begin_grab(&mut self) {
+        if !self.suspend_during_grab || self.grab_suspended {
+            return;
+        }
+        self.grab_suspended = true;
+        if let Some(ic) = self.ic.filter(|_| self.ic_focused) {
+            unsafe {
+                xcb_xim_unset_ic_focus(self.im, ic);
+            }
+            self.requests_pending = true;
+            self.ic_focused = false;
+            self.flush_if_enabled();
+        }
+    }
+
+    /// Inform the IME client that the pointer grab started with [`begin_grab`] ended.
+    ///
+    /// The focus of the input context is restored, unless it has been unset with
+    /// [`set_focus_window`] in the meantime, and the strings committed during the grab are
+    /// delivered in order.
+    ///
+    /// [`begin_grab`]: ImeClient::begin_grab
+    /// [`set_focus_window`]: ImeClient::set_focus_window
+    pub fn end_grab(&mut self) {
+        if !self.grab_suspended {
+            return;
+        }
+        self.grab_suspended = false;
+        if let Some(ic) = self.ic.filter(|_| self.focus_req && !self.ic_focused) {
+            unsafe {
+                xcb_xim_set_ic_focus(self.im, ic);
+            }
+            self.requests_pending = true;
+            self.ic_focused = true;
+            self.flush_if_enabled();
+        }
+        for (win, text) in std::mem::take(&mut self.grab_commits) {
+            self.deliver_commit(win, &text);
+        }
+    }
+
+    /// Set the position at which to place the IME window and return the previous one.
+    ///
+    /// This is the same as [`update_pos`], except that nothing is sent to the IME if neither the
+    /// window nor the position changed since the last call. Return `None` in that case and the
+    /// previously requested position otherwise, which is `(0, 0)` if no position has been set
+    /// before. This allows to tell whether the IME window moved and from where, e.g. to animate
+    /// an overlay.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn replace_pos(&mut self, win: Window, x: i16, y: i16) -> Option<(i16, i16)> {
+        let previous = self.pos_req;
+        if (previous.win, previous.x, previous.y) == (win.resource_id(), x, y) {
+            if self.ic.is_none() {
+                self.try_open_ic();
+            }
+            return None;
+        }
+        self.update_pos(win, x, y);
+        Some((previous.x, previous.y))
+    }
+
+    /// Inform the IME that `win` has been reparented.
+    ///
+    /// If `win` is the current window (set by [`update_pos`]), the attributes of the input
+    /// context are sent to the IME again as done by [`reapply_attrs`], so that the preedit stays
+    /// anchored to the window. [`process_event`] calls this automatically for `ReparentNotify`
+    /// events, thus this is only needed if those events are not passed to [`process_event`].
+    ///
+    /// Return value is the same as for [`update_pos`], `false` is also returned if `win` is not
+    /// the current window.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    /// [`reapply_attrs`]: ImeClient::reapply_attrs
+    /// [`process_event`]: ImeClient::process_event
+    pub fn notify_reparented(&mut self, win: Window) -> bool {
+        if win.resource_id() != self.pos_cur.win {
+            return false;
+        }
+        self.reapply_attrs()
+    }
+
+    /// Send the attributes of the input context to the IME again whenever the current window is
+    /// moved or resized.
+    ///
+    /// The spot location and the preedit and status areas are relative to the window, so most IME
+    /// servers place their windows correctly after the window moved. Some IME servers however
+    /// cache absolute positions, e.g. of the status window, which then lag behind while the
+    /// window is dragged. If enabled, a `ConfigureNotify` event of the current window (set by
+    /// [`update_pos`]) passed to [`process_event`] calls [`reapply_attrs`]. The application has
+    /// to select `STRUCTURE_NOTIFY` events on the window. Whether an IME server needs this cannot
+    /// be detected, so it is disabled by default.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    /// [`process_event`]: ImeClient::process_event
+    /// [`reapply_attrs`]: ImeClient::reapply_attrs
+    pub fn set_rehome_on_configure(&mut self, enable: bool) {
+        self.rehome_on_configure = enable;
+    }
+
+    /// Send all attributes of the input context to the IME again.
+    ///
+    /// The client and focus window, the spot location, the preedit area set with
+    /// [`notify_window_moved`] and the preedit colors are sent in one update, which brings the IME
+    /// server back in sync with the state of the [`ImeClient`]. A newly created input context,
+    /// e.g. after reconnecting to the IME server, receives all attributes anyway.
+    ///
+    /// Return value is the same as for [`update_pos`], `false` is also returned if there is no
+    /// input context.
+    ///
+    /// [`notify_window_moved`]: ImeClient::notify_window_moved
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn reapply_attrs(&mut self) -> bool {
+        if self.ic.is_none() {
+            return false;
+        }
+        // makes `send_pos_update` send the windows and preedit attributes again
+        self.pos_cur.win = 0;
+        if self.area_req.is_none() {
+            self.area_req = self.area_cur;
+        }
+        if self.status_area_req.is_none() {
+            self.status_area_req = self.status_area_cur;
+        }
+        self.preedit_attrs_pending = true;
+        self.request_pos_update()
+    }
+
+    /// Set the position at which to place the IME window and read back where the IME server placed
+    /// it.
+    ///
+    /// This is the same as [`update_pos`], except that it waits until the IME server processed the
+    /// update and then queries the spot location of the input context. IME servers may adjust the
+    /// spot, e.g. to keep the preedit on screen, so the returned position can differ from the
+    /// requested one.
+    ///
+    /// While waiting, events are read from the connection and passed to [`process_event`]; events
+    /// not handled by the IME client are kept and can be retrieved with
+    /// [`take_deferred_events`]. Waiting requires the [`ImeClient`] to be created with [`new`].
+    ///
+    /// Return `None` if the IME server did not respond in time.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    /// [`process_event`]: ImeClient::process_event
+    /// [`take_deferred_events`]: ImeClient::take_deferred_events
+    /// [`new`]: ImeClient::new
+    pub fn update_pos_confirmed(&mut self, win: Window, x: i16, y: i16) -> Option<(i16, i16)> {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        self.update_pos(win, x, y);
+        let updated = self.pump_events_until(deadline, |ime| {
+            ime.ic.is_some() && !ime.is_processing_pos_update
+        });
+        let ic = self.ic.filter(|_| updated)?;
+        // only the attribute names are relevant to the query, the value is ignored
+        let mut list = NestedList::default();
+        list.push_attr(self.im, &IcAttr::Spot(Point::default()));
+        if list.is_empty() {
+            return None;
+        }
+        unsafe {
+            self.spot_readback = None;
+            self.spot_readback_pending = xcb_xim_get_ic_values(
+                self.im,
+                ic,
+                Some(get_spot_callback),
+                self as *mut _ as _,
+                XCB_XIM_XNPreeditAttributes,
+                &list.as_raw(),
+                std::ptr::null_mut::<c_void>(),
+            );
+        }
+        if !self.spot_readback_pending {
+            return None;
+        }
+        self.pump_events_until(deadline, |ime| !ime.spot_readback_pending);
+        self.spot_readback_pending = false;
+        self.spot_readback.take()
+    }
+
+    /// Query the offset in characters at which the visible part of the preedit text of `win`
+    /// starts.
+    ///
+    /// IME servers may show only part of a long preedit, e.g. a full sentence being converted,
+    /// and report the start of the shown part as `XNVisiblePosition`. This is useful to scroll
+    /// an inline preedit drawn by the application accordingly, see [`PreeditInfo::current`].
+    ///
+    /// While waiting, events are read from the connection and passed to [`process_event`]; events
+    /// not handled by the IME client are kept and can be retrieved with
+    /// [`take_deferred_events`]. Waiting requires the [`ImeClient`] to be created with [`new`].
+    ///
+    /// Return `None` if `win` has no input context, the IME server does not support the
+    /// attribute or did not respond in time.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`take_deferred_events`]: ImeClient::take_deferred_events
+    /// [`new`]: ImeClient::new
+    #[cfg(feature = "preedit")]
+    pub fn preedit_visible_position(&mut self, win: Window) -> Option<u32> {
+        let ic = self.ic.filter(|_| self.pos_cur.win == win.resource_id())?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        unsafe {
+            self.visible_position_reply = None;
+            self.visible_position_pending = xcb_xim_get_ic_values(
+                self.im,
+                ic,
+                Some(get_visible_position_callback),
+                self as *mut _ as _,
+                XN_VISIBLE_POSITION.as_ptr(),
+                std::ptr::null_mut::<c_void>(),
+            );
+        }
+        if !self.visible_position_pending {
+            return None;
+        }
+        self.pump_events_until(deadline, |ime| !ime.visible_position_pending);
+        self.visible_position_pending = false;
+        self.visible_position_reply.take()
+    }
+
+    /// End the composition within `win` and return the text that was being composed.
+    ///
+    /// This resets the input context, like losing the focus does, and waits for the reply of the
+    /// IME server. XIM has no request to commit the preedit; instead the reply to a reset carries
+    /// the preedit text, which the IME server discards. The text is returned to the caller only,
+    /// it is not passed to the commit callbacks. IME servers that do not report the preedit reply
+    /// an empty string. The resets done by [`set_window_enabled`] and [`shutdown`] ignore the
+    /// reply and thereby discard the composition.
+    ///
+    /// While waiting, events are read from the connection and passed to [`process_event`]; events
+    /// not handled by the IME client are kept and can be retrieved with
+    /// [`take_deferred_events`]. Waiting requires the [`ImeClient`] to be created with [`new`].
+    ///
+    /// Return `None` if `win` has no input context or the IME server did not reply in time.
+    ///
+    /// [`set_window_enabled`]: ImeClient::set_window_enabled
+    /// [`shutdown`]: ImeClient::shutdown
+    /// [`process_event`]: ImeClient::process_event
+    /// [`take_deferred_events`]: ImeClient::take_deferred_events
+    /// [`new`]: ImeClient::new
+    pub fn commit_preedit(&mut self, win: Window) -> Option<String> {
+        let ic = self.ic.filter(|_| self.pos_cur.win == win.resource_id())?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        self.reset_reply = None;
+        self.reset_pending =
+            unsafe { xcb_xim_reset_ic(self.im, ic, Some(reset_ic_callback), self as *mut _ as _) };
+        if !self.reset_pending {
+            return None;
+        }
+        self.pump_events_until(deadline, |ime| !ime.reset_pending);
+        self.reset_pending = false;
+        self.reset_reply.take()
+    }
+
+    /// Wait for the next string committed by the IME.
+    ///
+    /// Events are read from the connection and passed to [`process_event`] until the IME commits
+    /// a string or `timeout` has passed. Events not handled by the IME client are kept and can be
+    /// retrieved with [`take_deferred_events`]. The awaited commit is returned instead of being
+    /// passed to the callback set by [`set_commit_string_cb`] or queued as [`ImeEvent::Commit`].
+    /// Waiting requires the [`ImeClient`] to be created with [`new`].
+    ///
+    /// Return `None` if nothing has been committed in time.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`take_deferred_events`]: ImeClient::take_deferred_events
+    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
+    /// [`new`]: ImeClient::new
+    pub fn wait_for_commit(&mut self, timeout: Duration) -> Option<(Window, String)> {
+        self.waiting_for_commit = true;
+        self.pump_events_until(Instant::now() + timeout, |ime| ime.awaited_commit.is_some());
+        self.waiting_for_commit = false;
+        self.awaited_commit.take()
+    }
+
+    /// Process the events of the IME until it is idle, e.g. before shutting down.
+    ///
+    /// Events are read from the connection and passed to [`process_event`] until no event for
+    /// the IME client arrived for a short period or `max` has passed, so that final commits are
+    /// not lost. Events not handled by the IME client are kept and can be retrieved with
+    /// [`take_deferred_events`]. Waiting requires the [`ImeClient`] to be created with [`new`].
+    ///
+    /// Return `true` if the IME became idle within `max`.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`take_deferred_events`]: ImeClient::take_deferred_events
+    /// [`new`]: ImeClient::new
+    pub fn drain_until_idle(&mut self, max: Duration) -> bool {
+        let deadline = Instant::now() + max;
+        self.last_handled_event = Instant::now();
+        self.pump_events_until(deadline, |ime| {
+            ime.last_handled_event.elapsed() >= IDLE_PERIOD
+        })
+    }
+
+    /// Take the events that were read from the connection while waiting for the IME server but
+    /// not handled by the IME client.
+    ///
+    /// Methods such as [`update_pos_confirmed`], [`wait_for_commit`] and [`drain_until_idle`]
+    /// read events from the connection themselves. Events that [`process_event`] would have
+    /// returned `false` for are kept until they are retrieved with this method, so that the
+    /// application can handle them as usual. The XIM messages of the IME server, which
+    /// [`process_event`] also returns `false` for, are left out as they have been applied already.
+    ///
+    /// [`update_pos_confirmed`]: ImeClient::update_pos_confirmed
+    /// [`wait_for_commit`]: ImeClient::wait_for_commit
+    /// [`drain_until_idle`]: ImeClient::drain_until_idle
+    /// [`process_event`]: ImeClient::process_event
+    pub fn take_deferred_events(&mut self) -> Vec<xcb::Event> {
+        std::mem::take(&mut self.deferred_events)
+    }
+
+    /// Run a minimal event loop for simple applications, e.g. command line tools.
+    ///
+    /// Events are read from the connection and passed to [`process_event`], which invokes the
+    /// registered callbacks, until `should_stop` returns `true`. Events not handled by the IME
+    /// client are discarded, so applications that need to handle events themselves have to run
+    /// their own loop. `should_stop` is checked before waiting for each event, so the loop only
+    /// stops once another event arrives. Running the loop requires the [`ImeClient`] to be
+    /// created with [`new`].
+    ///
+    /// Return `true` if `should_stop` returned `true` and `false` if the connection failed.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`new`]: ImeClient::new
+    pub fn run_blocking<F>(&mut self, should_stop: F) -> bool
+    where
+        F: Fn() -> bool,
+    {
+        let conn = match self.conn.clone() {
+            Some(conn) => conn,
+            None => return false,
+        };
+        while !should_stop() {
+            unsafe { xcb_flush(self.raw_conn) };
+            match conn.wait_for_event() {
+                Ok(event) => {
+                    self.process_event(&event);
+                }
+                // errors of requests do not affect the loop
+                Err(xcb::Error::Protocol(_)) => {}
+                Err(xcb::Error::Connection(_)) => return false,
+            }
+        }
+        true
+    }
+
+    /// Read events from the connection and pass them to [`process_event`] until `done` returns
+    /// `true` or `deadline` has passed.
+    ///
+    /// Return whether `done` returned `true`. Unhandled events are kept in `deferred_events`, the
+    /// time of the last event for the IME client, including the XIM messages consumed by
+    /// xcb-imdkit, is stored in `last_handled_event`.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    fn pump_events_until<F>(&mut self, deadline: Instant, mut done: F) -> bool
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        let conn = match self.conn.clone() {
+            Some(conn) => conn,
+            None => return false,
+        };
+        loop {
+            if done(self) {
+                return true;
+            }
+            unsafe { xcb_flush(self.raw_conn) };
+            match conn.poll_for_event() {
+                Ok(Some(event)) => match self.handle_event(&event) {
+                    // passing consumed XIM messages to `process_event` again would apply them twice
+                    Filtered::Ignored => self.deferred_events.push(event),
+                    Filtered::Consumed | Filtered::Used => {
+                        self.last_handled_event = Instant::now();
+                    }
+                },
+                Ok(None) if Instant::now() < deadline => std::thread::sleep(PUMP_INTERVAL),
+                _ => return false,
+            }
+        }
+    }
+
+    /// Inform the IME that `win` has moved, e.g. to another monitor.
+    ///
+    /// If `win` is the current window (set by [`update_pos`]), `area` is sent to the IME as the
+    /// preedit area along with the current spot location, so that the IME can adjust the
+    /// placement of the preedit without recreating the input context. The update is merged with
+    /// pending updates of [`update_pos`].
+    ///
+    /// Return value is the same as for [`update_pos`], `false` is also returned if `win` is not
+    /// the current window.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn notify_window_moved<R>(&mut self, win: Window, area: R) -> bool
+    where
+        R: Into<Rectangle>,
+    {
+        if win.resource_id() != self.pos_req.win {
+            return false;
+        }
+        self.area_req = Some(area.into().into());
+        self.request_pos_update()
+    }
+
+    /// Set the preedit area sent when the input context is created.
+    ///
+    /// Unlike [`notify_window_moved`] this does not require a current window. The area is sent
+    /// when the next input context is created, see [`new`], or with the next position update if
+    /// an input context already exists.
+    ///
+    /// [`notify_window_moved`]: ImeClient::notify_window_moved
+    /// [`new`]: ImeClient::new
+    pub fn set_initial_preedit_area<R>(&mut self, area: R)
+    where
+        R: Into<Rectangle>,
+    {
+        self.area_req = Some(area.into().into());
+    }
+
+    fn request_pos_update(&mut self) -> bool {
+        let res = match self.ic {
+            Some(_) if self.is_processing_pos_update => {
+                self.pos_update_queued = true;
+                false
+            }
+            Some(ic) => {
+                self.send_pos_update(ic);
+                true
+            }
+            _ => {
+                self.try_open_ic();
+                false
+            }
+        };
+        self.flush_if_enabled();
+        res
+    }
+
+    /// Flush the connection if requests have been sent since the last flush, unless disabled with
+    /// [`set_auto_flush`].
+    ///
+    /// [`set_auto_flush`]: ImeClient::set_auto_flush
+    fn flush_if_enabled(&mut self) {
+        if self.auto_flush && std::mem::take(&mut self.requests_pending) {
+            unsafe { xcb_flush(self.raw_conn) };
+        }
+    }
+
+    /// Flush the connection whenever requests have been sent to the IME server.
+    ///
+    /// If enabled, the connection is flushed before [`process_event`], [`update_pos`] and the
+    /// other methods sending requests return, so the requests reach the IME server even if the
+    /// event loop blocks waiting for events without flushing the connection first. Applications
+    /// that flush the connection themselves, e.g. once per iteration of their event loop, can
+    /// disable this to save some system calls. Calls that did not send anything, e.g. for events
+    /// unrelated to the IME, never flush. Enabled by default.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn set_auto_flush(&mut self, enable: bool) {
+        self.auto_flush = enable;
+    }
+
+    fn send_pos_update(&mut self, ic: xcb_xic_t) {
+        self.requests_pending = true;
+        self.is_processing_pos_update = true;
+        let spot = Point {
+            x: self.pos_req.x,
+            y: self.pos_req.y,
+        };
+        let mut list = NestedList::default();
+        list.push_attr(self.im, &IcAttr::Spot(spot));
+        if let Some(area) = self.area_req.take() {
+            list.push_attr(self.im, &IcAttr::Area(area.into()));
+            self.area_cur = Some(area);
+        }
+        if std::mem::take(&mut self.preedit_attrs_pending) {
+            self.preedit_attrs.push_to(self.im, &mut list);
+        }
+        let nested = list.as_raw();
+        let mut status_list = self.take_status_list();
+        let (status_name, status_nested) = status_attribute(&mut status_list);
+        if self.pos_req.win != self.pos_cur.win {
+            let w = &mut self.pos_req.win as *mut _;
+            unsafe {
+                xcb_xim_set_ic_values(
+                    self.im,
+                    ic,
+                    Some(update_pos_callback),
+                    self as *mut _ as _,
+                    XCB_XIM_XNClientWindow,
+                    w,
+                    XCB_XIM_XNFocusWindow,
+                    w,
+                    XCB_XIM_XNPreeditAttributes,
+                    &nested,
+                    status_name,
+                    &status_nested,
+                    std::ptr::null_mut::<c_void>(),
+                );
+            }
+        } else {
+            unsafe {
+                xcb_xim_set_ic_values(
+                    self.im,
+                    ic,
+                    Some(update_pos_callback),
+                    self as *mut _ as _,
+                    XCB_XIM_XNPreeditAttributes,
+                    &nested,
+                    status_name,
+                    &status_nested,
+                    std::ptr::null_mut::<c_void>(),
+                );
+            }
+        }
+        self.pos_cur = self.pos_req;
+    }
+
+    /// Build the status attributes to send with the next update of the input context.
+    fn take_status_list(&mut self) -> NestedList {
+        let mut list = NestedList::default();
+        if let Some(area) = self.status_area_req.take() {
+            list.push_attr(self.im, &IcAttr::Area(area.into()));
+            self.status_area_cur = Some(area);
+        }
+        list
+    }
+
+    fn send_preedit_attrs(&mut self) {
+        let ic = match self.ic {
+            Some(ic) => ic,
+            None => return,
+        };
+        let mut list = NestedList::default();
+        self.preedit_attrs.push_to(self.im, &mut list);
+        if list.is_empty() {
+            return;
+        }
+        unsafe {
+            xcb_xim_set_ic_values(
+                self.im,
+                ic,
+                None,
+                std::ptr::null_mut(),
+                XCB_XIM_XNPreeditAttributes,
+                &list.as_raw(),
+                std::ptr::null_mut::<c_void>(),
+            );
+        }
+        self.requests_pending = true;
+        self.flush_if_enabled();
+    }
+
+    /// Set the colors the IME uses to draw the preedit text.
+    ///
+    /// `foreground` and `background` are pixel values as used by X11, i.e. they depend on the
+    /// visual of the window. To specify colors as RGB values consult [`set_preedit_colors_rgb`].
+    /// The colors are kept and sent again whenever a new input context is created.
+    ///
+    /// [`set_preedit_colors_rgb`]: ImeClient::set_preedit_colors_rgb
+    pub fn set_preedit_colors(&mut self, foreground: u32, background: u32) {
+        self.preedit_attrs.foreground = Some(foreground);
+        self.preedit_attrs.background = Some(background);
+        self.send_preedit_attrs();
+    }
+
+    /// Set the colors the IME uses to draw the preedit text as RGB values.
+    ///
+    /// The RGB values are converted into pixel values for the default visual of the screen this
+    /// [`ImeClient`] has been created for. For visuals without direct color mapping the colors
+    /// are allocated in the default colormap of the screen. Allocated colors are cached and never
+    /// freed, so repeated calls with the same colors do not allocate again.
+    ///
+    /// Return `false` if the pixel values could not be determined, e.g. because the
+    /// [`ImeClient`] has been created with [`unsafe_new`] and has no access to the connection.
+    ///
+    /// [`unsafe_new`]: ImeClient::unsafe_new
+    pub fn set_preedit_colors_rgb(
+        &mut self,
+        foreground: (u8, u8, u8),
+        background: (u8, u8, u8),
+    ) -> bool {
+        match (self.rgb_to_pixel(foreground), self.rgb_to_pixel(background)) {
+            (Some(foreground), Some(background)) => {
+                self.set_preedit_colors(foreground, background);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Set a pixmap the IME draws behind the preedit text.
+    ///
+    /// Like the colors, the pixmap is kept and sent again whenever a new input context is
+    /// created. The pixmap has to remain valid as long as it is used by the IME.
+    ///
+    /// Return `false` without changing anything if `pixmap` does not belong to the screen this
+    /// [`ImeClient`] has been created for. The check requires the [`ImeClient`] to be created
+    /// with [`new`], otherwise it is skipped.
+    ///
+    /// [`new`]: ImeClient::new
+    pub fn set_preedit_background_pixmap(&mut self, pixmap: xcb::x::Pixmap) -> bool {
+        if let Some(conn) = self.conn.as_ref() {
+            let root = conn
+                .get_setup()
+                .roots()
+                .nth(self.screen_id as usize)
+                .map(|screen| screen.root());
+            let cookie = conn.send_request(&xcb::x::GetGeometry {
+                drawable: xcb::x::Drawable::Pixmap(pixmap),
+            });
+            match conn.wait_for_reply(cookie) {
+                Ok(geometry) if Some(geometry.root()) == root => {}
+                _ => return false,
+            }
+        }
+        self.preedit_attrs.background_pixmap = Some(pixmap.resource_id());
+        self.send_preedit_attrs();
+        true
+    }
+
+    /// Set the fonts the IME uses to draw the preedit text.
+    ///
+    /// `base_names` is a comma separated list of base font names as used by `XCreateFontSet`.
+    /// Like the colors, the font set is kept and sent again whenever a new input context is
+    /// created.
+    pub fn set_preedit_font_set(&mut self, base_names: &str) {
+        self.preedit_attrs.font_set = Some(base_names.to_owned());
+        self.send_preedit_attrs();
+    }
+
+    /// Set the distance in pixels between two lines of the preedit text.
+    ///
+    /// Like the colors, the line spacing is kept and sent again whenever a new input context is
+    /// created.
+    pub fn set_preedit_line_space(&mut self, line_space: u32) {
+        self.preedit_attrs.line_space = Some(line_space);
+        self.send_preedit_attrs();
+    }
+
+    fn rgb_to_pixel(&mut self, rgb: (u8, u8, u8)) -> Option<u32> {
+        if let Some(&pixel) = self.pixel_cache.get(&rgb) {
+            return Some(pixel);
+        }
+        let conn = self.conn.as_ref()?;
+        let screen = conn.get_setup().roots().nth(self.screen_id as usize)?;
+        let visual = screen
+            .allowed_depths()
+            .flat_map(|depth| depth.visuals())
+            .find(|visual| visual.visual_id() == screen.root_visual())?;
+        let (r, g, b) = rgb;
+        let pixel = match visual.class() {
+            xcb::x::VisualClass::TrueColor | xcb::x::VisualClass::DirectColor => {
+                scale_to_mask(r, visual.red_mask())
+                    | scale_to_mask(g, visual.green_mask())
+                    | scale_to_mask(b, visual.blue_mask())
+            }
+            _ => {
+                let cookie = conn.send_request(&xcb::x::AllocColor {
+                    cmap: screen.default_colormap(),
+                    red: (r as u16) << 8 | r as u16,
+                    green: (g as u16) << 8 | g as u16,
+                    blue: (b as u16) << 8 | b as u16,
+                });
+                conn.wait_for_reply(cookie).ok()?.pixel()
+            }
+        };
+        self.pixel_cache.insert(rgb, pixel);
+        Some(pixel)
+    }
+
+    /// Set how control characters within strings committed by the IME server are handled.
+    ///
+    /// The policy is applied after the string has been converted to UTF-8 and before it is passed
+    /// to the commit callbacks or queued as [`ImeEvent::Commit`]. The raw bytes passed to the
+    /// callback set with [`set_commit_raw_cb`] are not affected. Defaults to
+    /// [`ControlCharPolicy::Keep`].
+    ///
+    /// [`set_commit_raw_cb`]: ImeClient::set_commit_raw_cb
+    pub fn set_control_char_policy(&mut self, policy: ControlCharPolicy) {
+        self.control_char_policy = policy;
+    }
+
+    /// Set the commit, forward, preedit and status callbacks at once to call the methods of
+    /// `handler`.
+    ///
+    /// This replaces the callbacks set with [`set_commit_string_cb`], [`set_forward_event_cb`],
+    /// [`set_preedit_start_cb`], [`set_preedit_draw_cb`], [`set_preedit_done_cb`] and
+    /// [`set_status_draw_cb`]. The individual setters can still be used afterwards to override
+    /// single callbacks.
+    ///
+    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
+    /// [`set_forward_event_cb`]: ImeClient::set_forward_event_cb
+    /// [`set_preedit_start_cb`]: ImeClient::set_preedit_start_cb
+    /// [`set_preedit_draw_cb`]: ImeClient::set_preedit_draw_cb
+    /// [`set_preedit_done_cb`]: ImeClient::set_preedit_done_cb
+    /// [`set_status_draw_cb`]: ImeClient::set_status_draw_cb
+    pub fn set_handler<H>(&mut self, handler: H)
+    where
+        H: CompositionHandler + 'static,
+    {
+        handler::register(self, handler);
+    }
+
+    /// Set callback to be called once input composition is done.
+    ///
+    /// The window (set by [`update_pos`]) as well as the completed input are passed as arguments.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    pub fn set_commit_string_cb<F>(&mut self, f: F)
+    where
+        F: for<'a> FnMut(Window, &'a str) + 'static,
     {
         self.callbacks.commit_string = Some(Box::new(f));
     }
 
+    /// Remove the callback set with [`set_commit_string_cb`], releasing the captured state.
+    ///
+    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
+    pub fn clear_commit_string_cb(&mut self) {
+        self.callbacks.commit_string = None;
+    }
+
+    /// Set callback to be called once input composition is done, receiving the completed input
+    /// as [`CommitString`].
+    ///
+    /// Unlike the callback set by [`set_commit_string_cb`], the callback takes ownership of the
+    /// text and receives its length in bytes as reported by the IME server, which allows to
+    /// reserve capacity before appending it. The callback is called after the callback set by
+    /// [`set_commit_string_cb`].
+    ///
+    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
+    pub fn set_commit_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, CommitString) + 'static,
+    {
+        self.callbacks.commit = Some(Box::new(f));
+    }
+
+    /// Remove the callback set with [`set_commit_cb`], releasing the captured state.
+    ///
+    /// [`set_commit_cb`]: ImeClient::set_commit_cb
+    pub fn clear_commit_cb(&mut self) {
+        self.callbacks.commit = None;
+    }
+
+    /// Set callback to be called once input composition is done, receiving the completed input
+    /// as UTF-16.
+    ///
+    /// Characters outside the Basic Multilingual Plane are encoded as surrogate pairs. The
+    /// callback is called after the callback set by [`set_commit_string_cb`].
+    ///
+    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
+    pub fn set_commit_utf16_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, Vec<u16>) + 'static,
+    {
+        self.callbacks.commit_utf16 = Some(Box::new(f));
+    }
+
+    /// Remove the callback set with [`set_commit_utf16_cb`], releasing the captured state.
+    ///
+    /// [`set_commit_utf16_cb`]: ImeClient::set_commit_utf16_cb
+    pub fn clear_commit_utf16_cb(&mut self) {
+        self.callbacks.commit_utf16 = None;
+    }
+
+    /// Set callback receiving committed strings as sent by the IME server.
+    ///
+    /// This is meant for debugging encoding issues with IME servers. The callback receives the
+    /// current window (set by [`update_pos`]), the encoding used to decode the string and its
+    /// bytes before any conversion. It is called right before the string is decoded and passed to
+    /// the callback set by [`set_commit_string_cb`], also if [`use_event_queue`] has been called.
+    /// The encoding is `None` if the IME server negotiated an encoding unknown to this crate.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
+    /// [`use_event_queue`]: ImeClient::use_event_queue
+    pub fn set_commit_raw_cb<F>(&mut self, f: F)
+    where
+        F: for<'a> FnMut(Window, Option<Encoding>, &'a [u8]) + 'static,
+    {
+        self.callbacks.commit_raw = Some(Box::new(f));
+    }
+
+    /// Remove the callback set with [`set_commit_raw_cb`], releasing the captured state.
+    ///
+    /// [`set_commit_raw_cb`]: ImeClient::set_commit_raw_cb
+    pub fn clear_commit_raw_cb(&mut self) {
+        self.callbacks.commit_raw = None;
+    }
+
+    #[cfg(feature = "forward")]
     // Set callback for keypress/keyrelease events unhandled by the IME.
     //
     // The first argument passed is the window (set by [`update_pos`]), the second the key event.
@@ -616,12 +4425,111 @@ impl ImeClient {
         self.callbacks.forward_event = Some(Box::new(f));
     }
 
+    /// Remove the callback set with [`set_forward_event_cb`], releasing the captured state.
+    ///
+    /// [`set_forward_event_cb`]: ImeClient::set_forward_event_cb
+    #[cfg(feature = "forward")]
+    pub fn clear_forward_event_cb(&mut self) {
+        self.callbacks.forward_event = None;
+    }
+
+    /// Set callback for key events unhandled by the IME, with the key already decoded.
+    ///
+    /// This is an alternative to [`set_forward_event_cb`] that resolves the keysym and the
+    /// modifiers of the key event, see [`ForwardedKey`]. Both callbacks are called if both are
+    /// set. Resolving the keysym requires the [`ImeClient`] to be created with [`new`], otherwise
+    /// the keysym is `0`.
+    ///
+    /// [`set_forward_event_cb`]: ImeClient::set_forward_event_cb
+    /// [`new`]: ImeClient::new
+    #[cfg(feature = "forward")]
+    pub fn set_forward_key_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(ForwardedKey) + 'static,
+    {
+        self.callbacks.forward_key = Some(Box::new(f));
+    }
+
+    /// Remove the callback set with [`set_forward_key_cb`], releasing the captured state.
+    ///
+    /// [`set_forward_key_cb`]: ImeClient::set_forward_key_cb
+    #[cfg(feature = "forward")]
+    pub fn clear_forward_key_cb(&mut self) {
+        self.callbacks.forward_key = None;
+    }
+
+    #[cfg(feature = "forward")]
+    fn deliver_forwarded(&mut self, event: xcb::Event) {
+        let win = Window::new(self.pos_req.win);
+        if let Some(events) = self.events.as_mut() {
+            events.push(ImeEvent::Forward { win, event });
+            return;
+        }
+        if let Some(f) = self.callbacks.forward_event.as_mut() {
+            f(win, &event);
+        }
+        if self.callbacks.forward_key.is_some() {
+            let key = self.forwarded_key(&event);
+            if let (Some(key), Some(f)) = (key, self.callbacks.forward_key.as_mut()) {
+                f(key);
+            }
+        }
+    }
+
+    #[cfg(feature = "forward")]
+    fn forwarded_key(&mut self, event: &xcb::Event) -> Option<ForwardedKey> {
+        let synthetic = is_synthetic(event);
+        let (event, pressed) = match event {
+            xcb::Event::X(xcb::x::Event::KeyPress(event)) => (event, true),
+            xcb::Event::X(xcb::x::Event::KeyRelease(event)) => (event, false),
+            _ => return None,
+        };
+        let state = event.state().bits() as u16;
+        Some(ForwardedKey {
+            keysym: self.keysym_for(event.detail(), state),
+            keycode: event.detail(),
+            modifiers: Modifiers::from_bits_truncate(state),
+            pressed,
+            window: event.event(),
+            time: event.time(),
+            token: KeyToken {
+                time: event.time(),
+                keycode: event.detail(),
+                pressed,
+            },
+            synthetic,
+        })
+    }
+
+    /// Token of the key event most recently forwarded to the IME server by [`process_event`].
+    ///
+    /// Call this right after [`process_event`] returned `true` for a key event to match the event
+    /// against key events sent back by the IME server, see [`KeyToken`].
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    #[cfg(feature = "forward")]
+    pub fn last_forwarded_token(&self) -> Option<KeyToken> {
+        self.last_forwarded
+    }
+
+    /// Limit the size of the preedit text in bytes.
+    ///
+    /// Preedit texts sent by the IME server that are longer than `max` bytes of UTF-8 are
+    /// truncated at a character boundary, which protects the application from IME servers sending
+    /// unreasonably long preedit texts. Truncation is logged and reported by
+    /// [`PreeditInfo::truncated`]. The default limit is 64 KiB.
+    #[cfg(feature = "preedit")]
+    pub fn set_max_preedit_bytes(&mut self, max: usize) {
+        self.max_preedit_bytes = max;
+    }
+
     /// Callback called once the IME has been opened.
     ///
     /// The current window (set by [`update_pos`]) is supplied as argument.
     /// Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set.
     ///
     /// [`update_pos`]: ImeClient::update_pos
+    #[cfg(feature = "preedit")]
     pub fn set_preedit_start_cb<F>(&mut self, f: F)
     where
         F: FnMut(Window) + 'static,
@@ -629,13 +4537,23 @@ impl ImeClient {
         self.callbacks.preedit_start = Some(Box::new(f));
     }
 
+    /// Remove the callback set with [`set_preedit_start_cb`], releasing the captured state.
+    ///
+    /// [`set_preedit_start_cb`]: ImeClient::set_preedit_start_cb
+    #[cfg(feature = "preedit")]
+    pub fn clear_preedit_start_cb(&mut self) {
+        self.callbacks.preedit_start = None;
+    }
+
     /// Callback called whenever the text whitin the IME has changed.
     ///
     /// The current window (set by [`update_pos`]) is supplied as argument as well as
     /// [`PreeditInfo`], which contains, among other things, the current text of the IME.
-    /// Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set.
+    /// Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set. With other styles the
+    /// preedit text is not observable at all, as XIM offers no way to query it.
     ///
     /// [`update_pos`]: ImeClient::update_pos
+    #[cfg(feature = "preedit")]
     pub fn set_preedit_draw_cb<F>(&mut self, f: F)
     where
         F: for<'a> FnMut(Window, PreeditInfo<'a>) + 'static,
@@ -643,28 +4561,416 @@ impl ImeClient {
         self.callbacks.preedit_draw = Some(Box::new(f));
     }
 
+    /// Remove the callback set with [`set_preedit_draw_cb`], releasing the captured state.
+    ///
+    /// [`set_preedit_draw_cb`]: ImeClient::set_preedit_draw_cb
+    #[cfg(feature = "preedit")]
+    pub fn clear_preedit_draw_cb(&mut self) {
+        self.callbacks.preedit_draw = None;
+    }
+
     /// Callback called once the IME has been closed.
     ///
     /// The current window (set by [`update_pos`]) is supplied as argument.
     /// Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set.
     ///
     /// [`update_pos`]: ImeClient::update_pos
+    #[cfg(feature = "preedit")]
     pub fn set_preedit_done_cb<F>(&mut self, f: F)
     where
         F: FnMut(Window) + 'static,
     {
         self.callbacks.preedit_done = Some(Box::new(f));
     }
+
+    /// Remove the callback set with [`set_preedit_done_cb`], releasing the captured state.
+    ///
+    /// [`set_preedit_done_cb`]: ImeClient::set_preedit_done_cb
+    #[cfg(feature = "preedit")]
+    pub fn clear_preedit_done_cb(&mut self) {
+        self.callbacks.preedit_done = None;
+    }
+
+    /// Callback called with commits and the preedit updates following them.
+    ///
+    /// IME servers often commit part of the preedit and update the remaining preedit right
+    /// afterwards, e.g. for Japanese input. This callback combines both into one
+    /// [`CompositionUpdate`], so that the committed text is not displayed twice in between. A
+    /// commit is held back until the next preedit update or until the next event passed to
+    /// [`process_event`] turns out not to be one, in which case it is delivered without a
+    /// preedit update. Preedit updates without a preceding commit are delivered as well.
+    ///
+    /// This callback is called in addition to [`set_commit_string_cb`] and
+    /// [`set_preedit_draw_cb`]. Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set.
+    ///
+    /// [`process_event`]: ImeClient::process_event
+    /// [`set_commit_string_cb`]: ImeClient::set_commit_string_cb
+    /// [`set_preedit_draw_cb`]: ImeClient::set_preedit_draw_cb
+    #[cfg(feature = "preedit")]
+    pub fn set_composition_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, CompositionUpdate) + 'static,
+    {
+        self.callbacks.composition = Some(Box::new(f));
+    }
+
+    /// Remove the callback set with [`set_composition_cb`], releasing the captured state.
+    ///
+    /// [`set_composition_cb`]: ImeClient::set_composition_cb
+    #[cfg(feature = "preedit")]
+    pub fn clear_composition_cb(&mut self) {
+        self.callbacks.composition = None;
+    }
+
+    /// Callback called when the IME starts and stops drawing the preedit.
+    ///
+    /// This combines the callbacks set with [`set_preedit_start_cb`] and [`set_preedit_done_cb`],
+    /// which are called before this callback if set. Both are independent of the callback set
+    /// with [`set_preedit_draw_cb`]. The current window (set by [`update_pos`]) is supplied as
+    /// argument as well as the [`PreeditPhase`].
+    /// Calls callback only if [`InputStyle::PREEDIT_CALLBACKS`] is set.
+    ///
+    /// [`set_preedit_start_cb`]: ImeClient::set_preedit_start_cb
+    /// [`set_preedit_done_cb`]: ImeClient::set_preedit_done_cb
+    /// [`set_preedit_draw_cb`]: ImeClient::set_preedit_draw_cb
+    /// [`update_pos`]: ImeClient::update_pos
+    #[cfg(feature = "preedit")]
+    pub fn set_preedit_phase_cb<F>(&mut self, f: F)
+    where
+        F: FnMut(Window, PreeditPhase) + 'static,
+    {
+        self.callbacks.preedit_phase = Some(Box::new(f));
+    }
+
+    /// Remove the callback set with [`set_preedit_phase_cb`], releasing the captured state.
+    ///
+    /// [`set_preedit_phase_cb`]: ImeClient::set_preedit_phase_cb
+    #[cfg(feature = "preedit")]
+    pub fn clear_preedit_phase_cb(&mut self) {
+        self.callbacks.preedit_phase = None;
+    }
+
+    /// Callback called whenever the status of the IME has changed.
+    ///
+    /// The current window (set by [`update_pos`]) is supplied as argument as well as the status
+    /// text, which usually describes the current input mode.
+    /// Calls callback only if [`InputStyle::STATUS_CALLBACKS`] is set.
+    ///
+    /// [`update_pos`]: ImeClient::update_pos
+    #[cfg(feature = "status")]
+    pub fn set_status_draw_cb<F>(&mut self, f: F)
+    where
+        F: for<'a> FnMut(Window, &'a str) + 'static,
+    {
+        self.callbacks.status_draw = Some(Box::new(f));
+    }
+
+    /// Remove the callback set with [`set_status_draw_cb`], releasing the captured state.
+    ///
+    /// [`set_status_draw_cb`]: ImeClient::set_status_draw_cb
+    #[cfg(feature = "status")]
+    pub fn clear_status_draw_cb(&mut self) {
+        self.callbacks.status_draw = None;
+    }
+
+    /// Latest status text the IME sent for `win`, usually naming the active engine or input mode,
+    /// e.g. "Pinyin".
+    ///
+    /// XIM has no dedicated request for the active engine; IME servers like fcitx and IBus only
+    /// report it as status text, which is sent only if [`InputStyle::STATUS_CALLBACKS`] is set.
+    /// The text is what the callback set with [`set_status_draw_cb`] received last.
+    ///
+    /// Return `None` if no status text has been received for `win` since the IME was opened.
+    ///
+    /// [`set_status_draw_cb`]: ImeClient::set_status_draw_cb
+    #[cfg(feature = "status")]
+    pub fn current_engine(&self, win: Window) -> Option<&str> {
+        match &self.status_text {
+            Some((w, text)) if *w == win => Some(text),
+            _ => None,
+        }
+    }
 }
 
 impl Drop for ImeClient {
     fn drop(&mut self) {
+        self.close(false);
+    }
+}
+
+/// [`ImeClient`] borrowing its connection, see [`ImeClient::from_borrowed`].
+///
+/// Dereferences to the [`ImeClient`].
+pub struct BorrowedImeClient<'c> {
+    inner: Pin<Box<ImeClient>>,
+    _conn: PhantomData<&'c xcb::Connection>,
+}
+
+impl<'c> BorrowedImeClient<'c> {
+    /// Close the IME and release all resources, see [`ImeClient::shutdown`].
+    pub fn shutdown(self) {
+        self.inner.shutdown();
+    }
+}
+
+impl<'c> Deref for BorrowedImeClient<'c> {
+    type Target = ImeClient;
+
+    fn deref(&self) -> &ImeClient {
+        &self.inner
+    }
+}
+
+impl<'c> DerefMut for BorrowedImeClient<'c> {
+    fn deref_mut(&mut self) -> &mut ImeClient {
+        &mut self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STYLE_FLAGS: &[(InputStyle, &str)] = &[
+        (InputStyle::PREEDIT_CALLBACKS, "PREEDIT_CALLBACKS"),
+        (InputStyle::PREEDIT_AREA, "PREEDIT_AREA"),
+        (InputStyle::PREEDIT_POSITION, "PREEDIT_POSITION"),
+        (InputStyle::PREEDIT_NOTHING, "PREEDIT_NOTHING"),
+        (InputStyle::PREEDIT_NONE, "PREEDIT_NONE"),
+        (InputStyle::STATUS_AREA, "STATUS_AREA"),
+        (InputStyle::STATUS_CALLBACKS, "STATUS_CALLBACKS"),
+        (InputStyle::STATUS_NOTHING, "STATUS_NOTHING"),
+        (InputStyle::STATUS_NONE, "STATUS_NONE"),
+    ];
+
+    #[test]
+    fn input_style_round_trip() {
+        for combination in 0..1u32 << STYLE_FLAGS.len() {
+            let flags = STYLE_FLAGS
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| combination & 1 << i != 0)
+                .map(|(_, flag)| flag);
+            let style = flags
+                .clone()
+                .fold(InputStyle::DEFAULT, |style, &(flag, _)| style | flag);
+            assert_eq!(InputStyle::from_raw(style.to_raw()), style);
+            let names = flags.map(|&(_, name)| name).collect::<Vec<_>>();
+            let expected = if names.is_empty() {
+                "DEFAULT".to_owned()
+            } else {
+                names.join(" | ")
+            };
+            assert_eq!(style.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn input_style_display() {
+        assert_eq!(InputStyle::DEFAULT.to_string(), "DEFAULT");
+        assert_eq!(
+            (InputStyle::PREEDIT_POSITION | InputStyle::STATUS_NOTHING).to_string(),
+            "PREEDIT_POSITION | STATUS_NOTHING"
+        );
+    }
+
+    #[test]
+    fn input_style_from_raw_drops_unknown_bits() {
+        let unknown = !InputStyle::all().bits();
+        assert_eq!(InputStyle::from_raw(unknown), InputStyle::DEFAULT);
+        assert_eq!(
+            InputStyle::from_raw(unknown | InputStyle::PREEDIT_CALLBACKS.bits()),
+            InputStyle::PREEDIT_CALLBACKS
+        );
+    }
+
+    #[cfg(feature = "preedit")]
+    fn draw(chg_first: u32, chg_length: u32, text: &str, feedback: &[u32]) -> PreeditString {
+        PreeditString {
+            caret: chg_first.saturating_add(text.chars().count() as u32),
+            chg_first,
+            chg_length,
+            text: text.to_owned(),
+            feedback_array: feedback.to_vec(),
+            ..PreeditString::default()
+        }
+    }
+
+    #[cfg(feature = "preedit")]
+    fn preedit_of(text: &str) -> PreeditString {
+        let mut preedit = PreeditString::default();
+        let feedback = (1..=text.chars().count() as u32).collect::<Vec<_>>();
+        preedit.apply_draw(&draw(0, 0, text, &feedback), usize::MAX);
+        preedit
+    }
+
+    #[cfg(feature = "preedit")]
+    #[test]
+    fn preedit_insert() {
+        for (first, text, feedback) in [
+            (0, "xäbc", [9, 1, 2, 3]),
+            (1, "äxbc", [1, 9, 2, 3]),
+            (3, "äbcx", [1, 2, 3, 9]),
+        ] {
+            let mut preedit = preedit_of("äbc");
+            preedit.apply_draw(&draw(first, 0, "x", &[9]), usize::MAX);
+            assert_eq!(preedit.text, text);
+            assert_eq!(preedit.feedback_array, feedback);
+            assert_eq!(preedit.caret, first + 1);
+            assert_eq!(preedit.status, 0);
+        }
+    }
+
+    #[cfg(feature = "preedit")]
+    #[test]
+    fn preedit_delete() {
+        for (first, text, feedback) in [(0, "bc", [2, 3]), (1, "äc", [1, 3]), (2, "äb", [1, 2])] {
+            let mut preedit = preedit_of("äbc");
+            let mut change = draw(first, 1, "", &[]);
+            change.status = 0x03;
+            preedit.apply_draw(&change, usize::MAX);
+            assert_eq!(preedit.text, text);
+            assert_eq!(preedit.feedback_array, feedback);
+        }
+        let mut preedit = preedit_of("äbc");
+        let mut change = draw(0, 3, "", &[]);
+        change.status = 0x03;
+        preedit.apply_draw(&change, usize::MAX);
+        assert_eq!(preedit.text, "");
+        assert!(preedit.feedback_array.is_empty());
+        assert_eq!(preedit.status, 0x01);
+    }
+
+    #[cfg(feature = "preedit")]
+    #[test]
+    fn preedit_replace() {
+        for (first, text, feedback) in [
+            (0, "xycd", [8, 9, 3, 4]),
+            (1, "äxyd", [1, 8, 9, 4]),
+            (2, "äbxy", [1, 2, 8, 9]),
+        ] {
+            let mut preedit = preedit_of("äbcd");
+            preedit.apply_draw(&draw(first, 2, "xy", &[8, 9]), usize::MAX);
+            assert_eq!(preedit.text, text);
+            assert_eq!(preedit.feedback_array, feedback);
+        }
+    }
+
+    #[cfg(feature = "preedit")]
+    #[test]
+    fn preedit_status_bits() {
+        // no text: the text of the draw is ignored
+        let mut preedit = preedit_of("abc");
+        let mut change = draw(1, 1, "ignored", &[]);
+        change.status = 0x01;
+        preedit.apply_draw(&change, usize::MAX);
+        assert_eq!(preedit.text, "ac");
+        assert_eq!(preedit.feedback_array, [1, 3]);
+        // no feedback: the inserted characters get the default feedback
+        let mut preedit = preedit_of("abc");
+        let mut change = draw(1, 1, "xy", &[8, 9]);
+        change.status = 0x02;
+        preedit.apply_draw(&change, usize::MAX);
+        assert_eq!(preedit.text, "axyc");
+        assert_eq!(preedit.feedback_array, [1, 0, 0, 3]);
+        // missing feedback is padded
+        let mut preedit = preedit_of("abc");
+        preedit.apply_draw(&draw(3, 0, "xy", &[9]), usize::MAX);
+        assert_eq!(preedit.feedback_array, [1, 2, 3, 9, 0]);
+    }
+
+    #[cfg(feature = "preedit")]
+    #[test]
+    fn preedit_clamping() {
+        let mut preedit = preedit_of("abc");
+        preedit.apply_draw(&draw(10, 0, "x", &[9]), usize::MAX);
+        assert_eq!(preedit.text, "abcx");
+        assert_eq!(preedit.feedback_array, [1, 2, 3, 9]);
+        let mut preedit = preedit_of("abc");
+        preedit.apply_draw(&draw(1, 10, "x", &[9]), usize::MAX);
+        assert_eq!(preedit.text, "ax");
+        assert_eq!(preedit.feedback_array, [1, 9]);
+        let mut preedit = preedit_of("abc");
+        preedit.apply_draw(&draw(u32::MAX, u32::MAX, "x", &[9]), usize::MAX);
+        assert_eq!(preedit.text, "abcx");
+        assert_eq!(preedit.feedback_array, [1, 2, 3, 9]);
+    }
+
+    #[cfg(feature = "preedit")]
+    #[test]
+    fn preedit_limit_applies_to_whole_preedit() {
+        let mut preedit = PreeditString::default();
+        for first in 0..4 {
+            preedit.apply_draw(&draw(first, 0, "ä", &[9]), 5);
+        }
+        assert_eq!(preedit.text, "ää");
+        assert_eq!(preedit.feedback_array, [9, 9]);
+        assert!(preedit.truncated);
+    }
+
+    #[cfg(feature = "forward")]
+    fn key_event(response_type: u8) -> xcb_key_press_event_t {
+        xcb_key_press_event_t {
+            response_type,
+            detail: 38,
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+
+    #[cfg(feature = "forward")]
+    #[test]
+    fn forwarded_key_events_are_validated() {
         unsafe {
-            if let Some(ic) = self.ic {
-                xcb_xim_destroy_ic(self.im, ic, None, std::ptr::null_mut());
+            assert!(key_event_from_ptr(std::ptr::null()).is_none());
+            // errors, replies, ButtonPress, ClientMessage and an invalid code
+            for response_type in [0, 1, XCB_KEY_PRESS + 2, 33, 0xff] {
+                assert!(key_event_from_ptr(&key_event(response_type)).is_none());
             }
-            xcb_xim_close(self.im);
-            xcb_xim_destroy(self.im);
+            assert!(matches!(
+                key_event_from_ptr(&key_event(XCB_KEY_PRESS)),
+                Some(xcb::Event::X(xcb::x::Event::KeyPress(event))) if event.detail() == 38
+            ));
+            assert!(matches!(
+                key_event_from_ptr(&key_event(XCB_KEY_RELEASE | 0x80)),
+                Some(xcb::Event::X(xcb::x::Event::KeyRelease(_)))
+            ));
+        }
+    }
+
+    #[cfg(feature = "forward")]
+    #[test]
+    fn bogus_forwarded_event_is_dropped() {
+        // the user data is never dereferenced for invalid events, otherwise this would crash
+        // a ClientMessage
+        let mut event = key_event(33);
+        forward_event_callback(std::ptr::null_mut(), 0, &mut event, std::ptr::null_mut());
+        forward_event_callback(
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        );
+    }
+
+    #[test]
+    fn panics_do_not_unwind_into_c() {
+        // unwinding out of an `extern "C"` function aborts the process, failing the test
+        extern "C" fn callback() {
+            ffi_guard(|| panic!("panicking user callback"));
         }
+
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logged = messages.clone();
+        ImeClient::set_logger(move |msg| logged.lock().unwrap().push(msg.to_owned()));
+        callback();
+        assert!(messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|msg| msg.contains("caught panic")));
+        // a panicking logger must not unwind either
+        ImeClient::set_logger(|_| panic!("panicking logger"));
+        callback();
+        lock_logger().take();
     }
 }
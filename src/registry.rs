@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+
+use xcb::x::Window;
+
+use crate::ImeClient;
+
+/// One [`ImeClient`] per window.
+///
+/// [`ImeRegistry`] routes the events of an X connection to the [`ImeClient`] of the window they
+/// belong to, e.g. for daemons serving many windows, possibly on different screens, that need
+/// independent input contexts. Clients are created on demand by a closure and dropped when their
+/// window is destroyed.
+pub struct ImeRegistry<F> {
+    create: F,
+    clients: HashMap<Window, Pin<Box<ImeClient>>>,
+}
+
+impl<F> ImeRegistry<F>
+where
+    F: FnMut(Window) -> Option<Pin<Box<ImeClient>>>,
+{
+    /// Create a new [`ImeRegistry`] creating clients with `create`.
+    ///
+    /// `create` is called with the window that needs a client. It should call
+    /// [`ImeClient::update_pos`] for the window, so the input context is created for it. Return
+    /// `None` to leave the window without a client.
+    pub fn new(create: F) -> Self {
+        Self {
+            create,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// The client of `win`, if there is one.
+    pub fn get(&mut self, win: Window) -> Option<&mut ImeClient> {
+        self.clients.get_mut(&win).map(|ime| &mut **ime)
+    }
+
+    /// The client of `win`, created if there is none yet.
+    pub fn get_or_create(&mut self, win: Window) -> Option<&mut ImeClient> {
+        let create = &mut self.create;
+        let ime = match self.clients.entry(win) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => entry.insert(create(win)?),
+        };
+        Some(&mut **ime)
+    }
+
+    /// Remove the client of `win` and return it.
+    pub fn remove(&mut self, win: Window) -> Option<Pin<Box<ImeClient>>> {
+        self.clients.remove(&win)
+    }
+
+    /// Windows that have a client.
+    pub fn windows(&self) -> impl Iterator<Item = Window> + '_ {
+        self.clients.keys().copied()
+    }
+
+    /// Pass an event to the client it belongs to, see [`ImeClient::process_event`].
+    ///
+    /// Key and focus events go to the client of their window, which is created if needed. A
+    /// `DestroyNotify` drops the client of the destroyed window. All other events, including
+    /// the XIM messages of the IME servers, are passed to every client.
+    ///
+    /// Return `true` if a client handled the event.
+    pub fn process_event(&mut self, event: &xcb::Event) -> bool {
+        let win = match event {
+            xcb::Event::X(xcb::x::Event::KeyPress(event)) => event.event(),
+            xcb::Event::X(xcb::x::Event::KeyRelease(event)) => event.event(),
+            xcb::Event::X(xcb::x::Event::FocusIn(event)) => event.event(),
+            xcb::Event::X(xcb::x::Event::FocusOut(event)) => event.event(),
+            xcb::Event::X(xcb::x::Event::DestroyNotify(event)) => {
+                self.remove(event.window());
+                return false;
+            }
+            _ => {
+                let mut handled = false;
+                for ime in self.clients.values_mut() {
+                    handled |= ime.process_event(event);
+                }
+                return handled;
+            }
+        };
+        match self.get_or_create(win) {
+            Some(ime) => ime.process_event(event),
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use xcb::x::Window;
+
+use crate::ImeClient;
+
+#[derive(Default)]
+struct State {
+    commits: String,
+    #[cfg(feature = "preedit")]
+    preedit: String,
+}
+
+#[cfg(feature = "preedit")]
+fn register_preedit_callbacks(ime: &mut ImeClient, win: Window, state: &Rc<RefCell<State>>) {
+    let draw_state = state.clone();
+    ime.set_preedit_draw_cb(move |w, info| {
+        if w == win {
+            draw_state.borrow_mut().preedit = info.current().text.clone();
+        }
+    });
+    let done_state = state.clone();
+    ime.set_preedit_done_cb(move |w| {
+        if w == win {
+            done_state.borrow_mut().preedit.clear();
+        }
+    });
+}
+
+/// Text input for a single window.
+///
+/// [`TextInput`] wraps an [`ImeClient`] for the common case of a single text field. It registers
+/// the commit and preedit callbacks of the [`ImeClient`] and keeps track of the committed text and
+/// the text that is currently being composed, so the application only has to poll for the
+/// results. The [`ImeClient`] stays accessible for everything not covered by [`TextInput`].
+pub struct TextInput {
+    ime: Pin<Box<ImeClient>>,
+    win: Window,
+    state: Rc<RefCell<State>>,
+}
+
+impl TextInput {
+    /// Create a new [`TextInput`] for the window `win`.
+    ///
+    /// This replaces the callbacks set with [`ImeClient::set_commit_string_cb`],
+    /// [`ImeClient::set_preedit_draw_cb`] and [`ImeClient::set_preedit_done_cb`]. To receive the
+    /// preedit text, the [`ImeClient`] has to be created with
+    /// [`InputStyle::PREEDIT_CALLBACKS`](crate::InputStyle::PREEDIT_CALLBACKS).
+    pub fn new(mut ime: Pin<Box<ImeClient>>, win: Window) -> Self {
+        let state = Rc::new(RefCell::new(State::default()));
+        let commit_state = state.clone();
+        ime.set_commit_string_cb(move |w, text| {
+            if w == win {
+                commit_state.borrow_mut().commits.push_str(text);
+            }
+        });
+        #[cfg(feature = "preedit")]
+        register_preedit_callbacks(&mut ime, win, &state);
+        Self { ime, win, state }
+    }
+
+    /// Window this [`TextInput`] belongs to.
+    pub fn window(&self) -> Window {
+        self.win
+    }
+
+    /// Take the text committed since the last call.
+    pub fn take_commits(&mut self) -> String {
+        std::mem::take(&mut self.state.borrow_mut().commits)
+    }
+
+    /// Text that is currently being composed.
+    #[cfg(feature = "preedit")]
+    pub fn preedit(&self) -> String {
+        self.state.borrow().preedit.clone()
+    }
+
+    /// Let the IME client process XCB's events, see [`ImeClient::process_event`].
+    pub fn process_event(&mut self, event: &xcb::Event) -> bool {
+        self.ime.process_event(event)
+    }
+
+    /// Set the position at which to place the IME window within the window of this
+    /// [`TextInput`], see [`ImeClient::update_pos`].
+    pub fn update_pos(&mut self, x: i16, y: i16) -> bool {
+        self.ime.update_pos(self.win, x, y)
+    }
+
+    /// The wrapped [`ImeClient`].
+    pub fn ime(&self) -> &ImeClient {
+        &self.ime
+    }
+
+    /// The wrapped [`ImeClient`].
+    ///
+    /// Replacing the callbacks set by [`TextInput::new`] stops the [`TextInput`] from tracking
+    /// the committed and composed text.
+    pub fn ime_mut(&mut self) -> &mut ImeClient {
+        &mut self.ime
+    }
+
+    /// Return the wrapped [`ImeClient`].
+    pub fn into_inner(self) -> Pin<Box<ImeClient>> {
+        self.ime
+    }
+}
@@ -0,0 +1,956 @@
+//! A headless X server and a minimal XIM server for the integration tests.
+//!
+//! The XIM server implements just enough of the protocol for an [`ImeClient`] to open the IME,
+//! create an input context and forward key events to it. Every forwarded key press is answered
+//! by committing a fixed string, key releases are sent back to the client unused. Only the
+//! "Only-CM & Property-with-CM" X transport is supported, and since client and server run in the
+//! same process, messages are encoded in the native byte order.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::pin::Pin;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use xcb::x::{Atom, ClientMessageData, ClientMessageEvent, EventMask, Window};
+use xcb::{Raw, Xid, XidNew};
+use xcb_imdkit::{ImeClient, InputStyle};
+
+/// How long to wait for the IME client or server to react.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Name of the IME server, passed as `im_name` to [`ImeClient::new`].
+const IM_NAME: &str = "@im=xcb-imdkit-test";
+
+const SERVER_ATOM: &str = "@server=xcb-imdkit-test";
+
+pub const XIM_CONNECT: u8 = 1;
+const XIM_CONNECT_REPLY: u8 = 2;
+const XIM_DISCONNECT: u8 = 3;
+const XIM_DISCONNECT_REPLY: u8 = 4;
+const XIM_OPEN: u8 = 30;
+const XIM_OPEN_REPLY: u8 = 31;
+const XIM_CLOSE: u8 = 32;
+const XIM_CLOSE_REPLY: u8 = 33;
+const XIM_SET_EVENT_MASK: u8 = 37;
+const XIM_ENCODING_NEGOTIATION: u8 = 38;
+const XIM_ENCODING_NEGOTIATION_REPLY: u8 = 39;
+const XIM_QUERY_EXTENSION: u8 = 40;
+const XIM_QUERY_EXTENSION_REPLY: u8 = 41;
+const XIM_GET_IM_VALUES: u8 = 44;
+const XIM_GET_IM_VALUES_REPLY: u8 = 45;
+pub const XIM_CREATE_IC: u8 = 50;
+const XIM_CREATE_IC_REPLY: u8 = 51;
+const XIM_DESTROY_IC: u8 = 52;
+const XIM_DESTROY_IC_REPLY: u8 = 53;
+pub const XIM_SET_IC_VALUES: u8 = 54;
+const XIM_SET_IC_VALUES_REPLY: u8 = 55;
+const XIM_GET_IC_VALUES: u8 = 56;
+const XIM_GET_IC_VALUES_REPLY: u8 = 57;
+pub const XIM_FORWARD_EVENT: u8 = 60;
+const XIM_SYNC: u8 = 61;
+const XIM_SYNC_REPLY: u8 = 62;
+const XIM_COMMIT: u8 = 63;
+const XIM_RESET_IC: u8 = 64;
+const XIM_RESET_IC_REPLY: u8 = 65;
+
+pub const KEY_PRESS: u8 = 2;
+pub const KEY_RELEASE: u8 = 3;
+
+/// `flag` of XIM_FORWARD_EVENT asking for XIM_SYNC_REPLY.
+const XIM_SYNCHRONOUS: u16 = 0x0001;
+/// `flag` of XIM_COMMIT for a committed string without keysym.
+const XIM_LOOKUP_CHARS: u16 = 0x0002;
+
+const TYPE_SEPARATOR: u16 = 0;
+const TYPE_CARD32: u16 = 3;
+const TYPE_WINDOW: u16 = 5;
+const TYPE_XIM_STYLES: u16 = 10;
+const TYPE_XRECTANGLE: u16 = 11;
+const TYPE_XPOINT: u16 = 12;
+const TYPE_XFONTSET: u16 = 13;
+const TYPE_NESTED_LIST: u16 = 0x7fff;
+
+const IM_ATTRS: &[(&str, u16)] = &[("queryInputStyle", TYPE_XIM_STYLES)];
+
+const IC_ATTRS: &[(&str, u16)] = &[
+    ("inputStyle", TYPE_CARD32),
+    ("clientWindow", TYPE_WINDOW),
+    ("focusWindow", TYPE_WINDOW),
+    ("filterEvents", TYPE_CARD32),
+    ("preeditAttributes", TYPE_NESTED_LIST),
+    ("statusAttributes", TYPE_NESTED_LIST),
+    ("fontSet", TYPE_XFONTSET),
+    ("area", TYPE_XRECTANGLE),
+    ("areaNeeded", TYPE_XRECTANGLE),
+    ("colorMap", TYPE_CARD32),
+    ("stdColorMap", TYPE_CARD32),
+    ("foreground", TYPE_CARD32),
+    ("background", TYPE_CARD32),
+    ("backgroundPixmap", TYPE_CARD32),
+    ("spotLocation", TYPE_XPOINT),
+    ("lineSpace", TYPE_CARD32),
+    ("separatorofNestedList", TYPE_SEPARATOR),
+];
+
+/// PreeditPosition | StatusNothing, PreeditNothing | StatusNothing and
+/// PreeditCallbacks | StatusNothing.
+const INPUT_STYLES: &[u32] = &[0x0404, 0x0408, 0x0402];
+
+/// Headless X server, killed when dropped.
+pub struct Xvfb {
+    child: Child,
+    display: String,
+}
+
+impl Xvfb {
+    /// Start `Xvfb` on a free display.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `Xvfb` is not on `PATH`.
+    pub fn start() -> Self {
+        let found = std::env::var_os("PATH")
+            .iter()
+            .flat_map(std::env::split_paths)
+            .any(|dir| dir.join("Xvfb").is_file());
+        assert!(found, "the XIM tests require Xvfb, which is not on PATH");
+        let mut child = Command::new("Xvfb")
+            .args([
+                "-displayfd",
+                "1",
+                "-nolisten",
+                "tcp",
+                "-screen",
+                "0",
+                "640x480x24",
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start Xvfb");
+        // the display number is written once the server accepts connections
+        let mut display = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut display)
+            .expect("failed to read the display of Xvfb");
+        assert!(!display.trim().is_empty(), "Xvfb did not start");
+        Self {
+            child,
+            display: format!(":{}", display.trim()),
+        }
+    }
+
+    /// Open a new connection to the X server.
+    pub fn connect(&self) -> (Arc<xcb::Connection>, i32) {
+        let (conn, screen_id) =
+            xcb::Connection::connect(Some(&self.display)).expect("failed to connect to Xvfb");
+        (Arc::new(conn), screen_id)
+    }
+}
+
+impl Drop for Xvfb {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Behavior of the [`XimServer`].
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    /// String committed for every forwarded key press, nothing is committed if empty.
+    pub commit: String,
+    /// Forward event mask sent with XIM_SET_EVENT_MASK after creating an input context. Without
+    /// it, the client keeps its default.
+    pub forward_event_mask: Option<u32>,
+    /// Time the server takes before answering each request.
+    pub reply_delay: Duration,
+}
+
+/// What the [`XimServer`] received from its clients.
+#[derive(Debug, Default)]
+pub struct ServerLog {
+    /// Major opcodes of the received requests, in order.
+    pub requests: Vec<u8>,
+    /// Latest value of every input context attribute set by XIM_CREATE_IC or
+    /// XIM_SET_IC_VALUES. Attributes of nested lists are prefixed with the name of the list, e.g.
+    /// `preeditAttributes/spotLocation`.
+    pub ic_values: HashMap<String, Vec<u8>>,
+    /// Response types of the forwarded events.
+    pub forwarded: Vec<u8>,
+}
+
+/// Minimal XIM server running on its own connection and thread, stopped when dropped.
+///
+/// Stopping the server closes its connection, which destroys its windows and releases the
+/// selection of the IME server, just as an IME server that quits.
+pub struct XimServer {
+    log: Arc<Mutex<ServerLog>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl XimServer {
+    /// Start the server and wait until clients can connect to it.
+    pub fn start(xvfb: &Xvfb, config: ServerConfig) -> Self {
+        let log = Arc::new(Mutex::new(ServerLog::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (conn, screen_id) = xvfb.connect();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let thread = {
+            let log = log.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut server = Server::new(conn, screen_id, config, log);
+                ready_tx.send(()).unwrap();
+                server.run(&stop);
+            })
+        };
+        ready_rx
+            .recv_timeout(TIMEOUT)
+            .expect("XIM server did not start");
+        Self {
+            log,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// What the server received so far.
+    pub fn log(&self) -> MutexGuard<'_, ServerLog> {
+        self.log.lock().unwrap()
+    }
+}
+
+impl Drop for XimServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() && !std::thread::panicking() {
+                panic!("XIM server panicked");
+            }
+        }
+    }
+}
+
+struct Atoms {
+    server: Atom,
+    xim_servers: Atom,
+    locales: Atom,
+    transport: Atom,
+    xconnect: Atom,
+    protocol: Atom,
+    more_data: Atom,
+}
+
+struct Server {
+    conn: Arc<xcb::Connection>,
+    root: Window,
+    owner: Window,
+    atoms: Atoms,
+    config: ServerConfig,
+    log: Arc<Mutex<ServerLog>>,
+    /// Client communication window for each of the windows created for the clients.
+    clients: HashMap<Window, Window>,
+    /// Fragments of messages split into several _XIM_MOREDATA messages.
+    fragments: HashMap<Window, Vec<u8>>,
+    /// Bytes already read from properties that are still appended to.
+    consumed: HashMap<Atom, u32>,
+    next_property: u32,
+    next_ic: u16,
+}
+
+impl Server {
+    fn new(
+        conn: Arc<xcb::Connection>,
+        screen_id: i32,
+        config: ServerConfig,
+        log: Arc<Mutex<ServerLog>>,
+    ) -> Self {
+        let root = conn
+            .get_setup()
+            .roots()
+            .nth(screen_id as usize)
+            .unwrap()
+            .root();
+        let intern = |name: &str| {
+            let cookie = conn.send_request(&xcb::x::InternAtom {
+                only_if_exists: false,
+                name: name.as_bytes(),
+            });
+            conn.wait_for_reply(cookie).unwrap().atom()
+        };
+        let atoms = Atoms {
+            server: intern(SERVER_ATOM),
+            xim_servers: intern("XIM_SERVERS"),
+            locales: intern("LOCALES"),
+            transport: intern("TRANSPORT"),
+            xconnect: intern("_XIM_XCONNECT"),
+            protocol: intern("_XIM_PROTOCOL"),
+            more_data: intern("_XIM_MOREDATA"),
+        };
+        let mut server = Self {
+            root,
+            owner: Window::none(),
+            atoms,
+            config,
+            log,
+            clients: HashMap::new(),
+            fragments: HashMap::new(),
+            consumed: HashMap::new(),
+            next_property: 0,
+            next_ic: 1,
+            conn,
+        };
+        server.owner = server.create_window();
+        server.register();
+        server
+    }
+
+    /// Own the selection of the server and list it in `XIM_SERVERS` of the root window.
+    fn register(&self) {
+        self.conn.send_request(&xcb::x::SetSelectionOwner {
+            owner: self.owner,
+            selection: self.atoms.server,
+            time: xcb::x::CURRENT_TIME,
+        });
+        let cookie = self.conn.send_request(&xcb::x::GetProperty {
+            delete: false,
+            window: self.root,
+            property: self.atoms.xim_servers,
+            r#type: xcb::x::ATOM_ATOM,
+            long_offset: 0,
+            long_length: 1024,
+        });
+        let reply = self.conn.wait_for_reply(cookie).unwrap();
+        let mut servers = reply.value::<Atom>().to_vec();
+        if !servers.contains(&self.atoms.server) {
+            servers.push(self.atoms.server);
+        }
+        self.conn.send_request(&xcb::x::ChangeProperty {
+            mode: xcb::x::PropMode::Replace,
+            window: self.root,
+            property: self.atoms.xim_servers,
+            r#type: xcb::x::ATOM_ATOM,
+            data: &servers,
+        });
+        self.conn.flush().unwrap();
+    }
+
+    fn create_window(&self) -> Window {
+        let window = self.conn.generate_id();
+        self.conn.send_request(&xcb::x::CreateWindow {
+            depth: 0,
+            wid: window,
+            parent: self.root,
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+            border_width: 0,
+            class: xcb::x::WindowClass::InputOnly,
+            visual: xcb::x::COPY_FROM_PARENT,
+            value_list: &[],
+        });
+        window
+    }
+
+    fn run(&mut self, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            match self.conn.poll_for_event() {
+                Ok(Some(xcb::Event::X(xcb::x::Event::SelectionRequest(event)))) => {
+                    self.answer_selection_request(&event)
+                }
+                Ok(Some(xcb::Event::X(xcb::x::Event::ClientMessage(event)))) => {
+                    self.handle_client_message(&event)
+                }
+                Ok(_) => std::thread::sleep(Duration::from_millis(1)),
+                Err(xcb::Error::Protocol(err)) => panic!("XIM server caused an error: {:?}", err),
+                Err(err) => panic!("XIM server lost its connection: {}", err),
+            }
+            self.conn.flush().unwrap();
+        }
+    }
+
+    /// Tell the client the supported locales and transports.
+    fn answer_selection_request(&self, event: &xcb::x::SelectionRequestEvent) {
+        let value: &[u8] = if event.target() == self.atoms.locales {
+            b"@locale=C,POSIX,en_US,en_US.UTF-8"
+        } else if event.target() == self.atoms.transport {
+            b"@transport=X/"
+        } else {
+            b""
+        };
+        // obsolete clients do not name a property
+        let property = if event.property() == Atom::none() {
+            event.target()
+        } else {
+            event.property()
+        };
+        self.conn.send_request(&xcb::x::ChangeProperty {
+            mode: xcb::x::PropMode::Replace,
+            window: event.requestor(),
+            property,
+            r#type: event.target(),
+            data: value,
+        });
+        let notify = xcb::x::SelectionNotifyEvent::new(
+            event.time(),
+            event.requestor(),
+            event.selection(),
+            event.target(),
+            property,
+        );
+        self.conn.send_request(&xcb::x::SendEvent {
+            propagate: false,
+            destination: xcb::x::SendEventDest::Window(event.requestor()),
+            event_mask: EventMask::NO_EVENT,
+            event: &notify,
+        });
+    }
+
+    fn handle_client_message(&mut self, event: &ClientMessageEvent) {
+        let window = event.window();
+        if event.r#type() == self.atoms.xconnect && window == self.owner {
+            if let ClientMessageData::Data32(data) = event.data() {
+                self.accept(data[0]);
+            }
+            return;
+        }
+        if !self.clients.contains_key(&window) {
+            return;
+        }
+        if event.r#type() == self.atoms.more_data {
+            if let ClientMessageData::Data8(data) = event.data() {
+                self.fragments
+                    .entry(window)
+                    .or_default()
+                    .extend_from_slice(&data);
+            }
+            return;
+        }
+        if event.r#type() != self.atoms.protocol {
+            return;
+        }
+        let message = match event.data() {
+            ClientMessageData::Data8(data) => {
+                let mut message = self.fragments.remove(&window).unwrap_or_default();
+                message.extend_from_slice(&data);
+                message
+            }
+            ClientMessageData::Data32(data) => {
+                self.read_property(window, Atom::new(data[1]), data[0])
+            }
+            ClientMessageData::Data16(_) => return,
+        };
+        let length = 4 + 4 * u16::from_ne_bytes([message[2], message[3]]) as usize;
+        self.handle_request(window, &message[..length]);
+    }
+
+    /// Create a communication window for the client with the window `client` and tell it.
+    fn accept(&mut self, client: u32) {
+        let client = Window::new(client);
+        let window = self.create_window();
+        self.clients.insert(window, client);
+        // major and minor transport version 0, up to 20 bytes per ClientMessage
+        let data = ClientMessageData::Data32([window.resource_id(), 0, 0, 20, 0]);
+        let event = ClientMessageEvent::new(client, self.atoms.xconnect, data);
+        self.send_event(client, &event);
+    }
+
+    /// Read `length` bytes the client appended to `property` of `window`.
+    fn read_property(&mut self, window: Window, property: Atom, length: u32) -> Vec<u8> {
+        let offset = self.consumed.remove(&property).unwrap_or(0);
+        let cookie = self.conn.send_request(&xcb::x::GetProperty {
+            delete: true,
+            window,
+            property,
+            r#type: xcb::x::ATOM_ANY,
+            long_offset: offset / 4,
+            // XIM messages are padded to a multiple of 4 bytes
+            long_length: length / 4,
+        });
+        let reply = self.conn.wait_for_reply(cookie).unwrap();
+        // the property is only deleted once it has been read completely
+        if reply.bytes_after() != 0 {
+            self.consumed.insert(property, offset + length);
+        }
+        reply.value::<u8>()[..length as usize].to_vec()
+    }
+
+    fn send_event(&self, window: Window, event: &ClientMessageEvent) {
+        self.conn.send_request(&xcb::x::SendEvent {
+            propagate: false,
+            destination: xcb::x::SendEventDest::Window(window),
+            event_mask: EventMask::NO_EVENT,
+            event,
+        });
+    }
+
+    /// Send a message to the client of the communication window `window`.
+    fn send(&mut self, window: Window, message: Message) {
+        let client = self.clients[&window];
+        let message = message.finish();
+        let data = if message.len() <= 20 {
+            let mut data = [0; 20];
+            data[..message.len()].copy_from_slice(&message);
+            ClientMessageData::Data8(data)
+        } else {
+            // longer messages are passed in a property, a new one each time
+            let name = format!("_XIM_TEST_SERVER_{}", self.next_property);
+            self.next_property += 1;
+            let cookie = self.conn.send_request(&xcb::x::InternAtom {
+                only_if_exists: false,
+                name: name.as_bytes(),
+            });
+            let property = self.conn.wait_for_reply(cookie).unwrap().atom();
+            self.conn.send_request(&xcb::x::ChangeProperty {
+                mode: xcb::x::PropMode::Append,
+                window: client,
+                property,
+                r#type: xcb::x::ATOM_STRING,
+                data: &message[..],
+            });
+            ClientMessageData::Data32([message.len() as u32, property.resource_id(), 0, 0, 0])
+        };
+        let event = ClientMessageEvent::new(client, self.atoms.protocol, data);
+        self.send_event(client, &event);
+    }
+
+    fn handle_request(&mut self, window: Window, request: &[u8]) {
+        let opcode = request[0];
+        self.log.lock().unwrap().requests.push(opcode);
+        std::thread::sleep(self.config.reply_delay);
+        let body = &request[4..];
+        // XIM_CONNECT and XIM_DISCONNECT do not start with the input method ID
+        let im = if body.len() >= 2 { card16(body, 0) } else { 0 };
+        match opcode {
+            XIM_CONNECT => {
+                // protocol version 1.0
+                let reply = Message::new(XIM_CONNECT_REPLY).card16(1).card16(0);
+                self.send(window, reply);
+            }
+            XIM_DISCONNECT => self.send(window, Message::new(XIM_DISCONNECT_REPLY)),
+            XIM_OPEN => self.send(window, open_reply()),
+            XIM_CLOSE => {
+                let reply = Message::new(XIM_CLOSE_REPLY).card16(im).card16(0);
+                self.send(window, reply);
+            }
+            XIM_QUERY_EXTENSION => {
+                // no extensions
+                let reply = Message::new(XIM_QUERY_EXTENSION_REPLY).card16(im).card16(0);
+                self.send(window, reply);
+            }
+            XIM_ENCODING_NEGOTIATION => {
+                let reply = Message::new(XIM_ENCODING_NEGOTIATION_REPLY)
+                    .card16(im)
+                    .card16(0)
+                    .card16(utf8_index(body))
+                    .card16(0);
+                self.send(window, reply);
+            }
+            XIM_GET_IM_VALUES => {
+                let mut styles = Message::default()
+                    .card16(INPUT_STYLES.len() as u16)
+                    .card16(0);
+                for &style in INPUT_STYLES {
+                    styles = styles.card32(style);
+                }
+                let attrs = Message::default().attr(0, &styles.0);
+                let reply = Message::new(XIM_GET_IM_VALUES_REPLY)
+                    .card16(im)
+                    .card16(attrs.0.len() as u16)
+                    .bytes(&attrs.0);
+                self.send(window, reply);
+            }
+            XIM_CREATE_IC => {
+                let length = card16(body, 2) as usize;
+                self.store_ic_values(&body[4..4 + length]);
+                let ic = self.next_ic;
+                self.next_ic += 1;
+                let reply = Message::new(XIM_CREATE_IC_REPLY).card16(im).card16(ic);
+                self.send(window, reply);
+                if let Some(mask) = self.config.forward_event_mask {
+                    // the events to forward are synchronous as well
+                    let event_mask = Message::new(XIM_SET_EVENT_MASK)
+                        .card16(im)
+                        .card16(ic)
+                        .card32(mask)
+                        .card32(mask);
+                    self.send(window, event_mask);
+                }
+            }
+            XIM_DESTROY_IC => {
+                let reply = Message::new(XIM_DESTROY_IC_REPLY)
+                    .card16(im)
+                    .card16(card16(body, 2));
+                self.send(window, reply);
+            }
+            XIM_SET_IC_VALUES => {
+                let length = card16(body, 4) as usize;
+                self.store_ic_values(&body[8..8 + length]);
+                let reply = Message::new(XIM_SET_IC_VALUES_REPLY)
+                    .card16(im)
+                    .card16(card16(body, 2));
+                self.send(window, reply);
+            }
+            XIM_GET_IC_VALUES => {
+                let reply = self.get_ic_values_reply(body);
+                self.send(window, reply);
+            }
+            XIM_FORWARD_EVENT => self.handle_forward_event(window, body),
+            XIM_SYNC => {
+                let reply = Message::new(XIM_SYNC_REPLY)
+                    .card16(im)
+                    .card16(card16(body, 2));
+                self.send(window, reply);
+            }
+            XIM_RESET_IC => {
+                // nothing is being composed
+                let reply = Message::new(XIM_RESET_IC_REPLY)
+                    .card16(im)
+                    .card16(card16(body, 2))
+                    .card16(0);
+                self.send(window, reply);
+            }
+            _ => {}
+        }
+    }
+
+    /// Record the attributes in the LISTofXICATTRIBUTE `list`.
+    fn store_ic_values(&mut self, list: &[u8]) {
+        let mut log = self.log.lock().unwrap();
+        for (name, value) in parse_ic_attrs(list, "") {
+            log.ic_values.insert(name, value);
+        }
+    }
+
+    /// Answer with the stored values of the requested attributes that are not nested lists.
+    fn get_ic_values_reply(&self, body: &[u8]) -> Message {
+        let length = card16(body, 4) as usize;
+        let log = self.log.lock().unwrap();
+        let mut attrs = Message::default();
+        for id in body[6..6 + length].chunks_exact(2) {
+            let id = u16::from_ne_bytes([id[0], id[1]]);
+            let value = IC_ATTRS
+                .get(id as usize)
+                .and_then(|&(name, _)| log.ic_values.get(name));
+            if let Some(value) = value {
+                attrs = attrs.attr(id, value);
+            }
+        }
+        Message::new(XIM_GET_IC_VALUES_REPLY)
+            .card16(card16(body, 0))
+            .card16(card16(body, 2))
+            .card16(attrs.0.len() as u16)
+            .card16(0)
+            .bytes(&attrs.0)
+    }
+
+    fn handle_forward_event(&mut self, window: Window, body: &[u8]) {
+        let (im, ic, flag) = (card16(body, 0), card16(body, 2), card16(body, 4));
+        let event = &body[8..40];
+        let response_type = event[0] & 0x7f;
+        self.log.lock().unwrap().forwarded.push(response_type);
+        if response_type == KEY_PRESS && !self.config.commit.is_empty() {
+            let text = self.config.commit.as_bytes();
+            let commit = Message::new(XIM_COMMIT)
+                .card16(im)
+                .card16(ic)
+                .card16(XIM_LOOKUP_CHARS)
+                .card16(text.len() as u16)
+                .bytes(text);
+            self.send(window, commit);
+        } else {
+            // give the key back unused
+            let forward = Message::new(XIM_FORWARD_EVENT)
+                .card16(im)
+                .card16(ic)
+                .card16(0)
+                .card16(card16(body, 6))
+                .bytes(event);
+            self.send(window, forward);
+        }
+        if flag & XIM_SYNCHRONOUS != 0 {
+            let reply = Message::new(XIM_SYNC_REPLY).card16(im).card16(ic);
+            self.send(window, reply);
+        }
+    }
+}
+
+/// XIM_OPEN_REPLY listing [`IM_ATTRS`] and [`IC_ATTRS`], the index is used as attribute ID.
+fn open_reply() -> Message {
+    let attr_list = |attrs: &[(&str, u16)]| {
+        let mut list = Message::default();
+        for (id, &(name, kind)) in attrs.iter().enumerate() {
+            list = list
+                .card16(id as u16)
+                .card16(kind)
+                .card16(name.len() as u16)
+                .bytes(name.as_bytes())
+                .pad(2 + name.len());
+        }
+        list.0
+    };
+    let im_attrs = attr_list(IM_ATTRS);
+    let ic_attrs = attr_list(IC_ATTRS);
+    Message::new(XIM_OPEN_REPLY)
+        .card16(1)
+        .card16(im_attrs.len() as u16)
+        .bytes(&im_attrs)
+        .card16(ic_attrs.len() as u16)
+        .card16(0)
+        .bytes(&ic_attrs)
+}
+
+/// Index of UTF-8 in the encodings offered by XIM_ENCODING_NEGOTIATION, the first encoding is
+/// used if UTF-8 is not offered.
+fn utf8_index(body: &[u8]) -> u16 {
+    let length = card16(body, 2) as usize;
+    let mut encodings = &body[4..4 + length];
+    let mut index = 0;
+    // LISTofSTR, each STR being a CARD8 length followed by the name
+    while let Some((&len, rest)) = encodings.split_first() {
+        if &rest[..len as usize] == b"UTF-8" {
+            return index;
+        }
+        encodings = &rest[len as usize..];
+        index += 1;
+    }
+    0
+}
+
+/// Parse the LISTofXICATTRIBUTE `list` into names and values, flattening nested lists.
+fn parse_ic_attrs(mut list: &[u8], prefix: &str) -> Vec<(String, Vec<u8>)> {
+    let mut attrs = Vec::new();
+    while list.len() >= 4 {
+        let (id, length) = (card16(list, 0), card16(list, 2) as usize);
+        let value = &list[4..4 + length];
+        list = &list[(4 + length + pad(length)).min(list.len())..];
+        let (name, kind) = match IC_ATTRS.get(id as usize) {
+            Some(&attr) => attr,
+            None => continue,
+        };
+        let name = format!("{}{}", prefix, name);
+        match kind {
+            TYPE_SEPARATOR => {}
+            TYPE_NESTED_LIST => attrs.extend(parse_ic_attrs(value, &format!("{}/", name))),
+            _ => attrs.push((name, value.to_vec())),
+        }
+    }
+    attrs
+}
+
+fn card16(data: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([data[offset], data[offset + 1]])
+}
+
+fn pad(length: usize) -> usize {
+    (4 - length % 4) % 4
+}
+
+/// Builder for XIM messages.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    /// Start a message with the header for `opcode`, the length is filled in by `finish`.
+    fn new(opcode: u8) -> Self {
+        Self(vec![opcode, 0, 0, 0])
+    }
+
+    fn card16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    fn card32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    /// Pad for a preceding item of `length` bytes.
+    fn pad(mut self, length: usize) -> Self {
+        self.0.resize(self.0.len() + pad(length), 0);
+        self
+    }
+
+    /// Append an XIMATTRIBUTE or XICATTRIBUTE.
+    fn attr(self, id: u16, value: &[u8]) -> Self {
+        self.card16(id)
+            .card16(value.len() as u16)
+            .bytes(value)
+            .pad(value.len())
+    }
+
+    /// Pad the message and fill in its length.
+    fn finish(self) -> Vec<u8> {
+        let mut message = self.0;
+        message.resize(message.len() + pad(message.len()), 0);
+        let length = ((message.len() - 4) / 4) as u16;
+        message[2..4].copy_from_slice(&length.to_ne_bytes());
+        message
+    }
+}
+
+/// Value of an XPoint attribute.
+pub fn point(x: i16, y: i16) -> Vec<u8> {
+    [x.to_ne_bytes(), y.to_ne_bytes()].concat()
+}
+
+/// Value of a CARD32 or Window attribute.
+pub fn card32(value: u32) -> Vec<u8> {
+    value.to_ne_bytes().to_vec()
+}
+
+/// An [`ImeClient`] on its own [`Xvfb`], connecting to an [`XimServer`].
+pub struct Setup {
+    // dropped in this order, the X server goes last
+    pub ime: Pin<Box<ImeClient>>,
+    pub conn: Arc<xcb::Connection>,
+    /// The window passed to [`ImeClient::update_pos`].
+    pub win: Window,
+    /// The running IME server, `None` after [`Setup::stop_server`].
+    pub server: Option<XimServer>,
+    pub xvfb: Xvfb,
+}
+
+impl Setup {
+    /// Start `Xvfb` and an [`XimServer`] with `config`, and create an [`ImeClient`] for it.
+    ///
+    /// The input context is created once the window is passed to [`ImeClient::update_pos`].
+    pub fn new(config: ServerConfig) -> Self {
+        let xvfb = Xvfb::start();
+        let server = XimServer::start(&xvfb, config);
+        let (conn, screen_id) = xvfb.connect();
+        let win = create_window(&conn, screen_id);
+        let ime = ImeClient::new(
+            conn.clone(),
+            screen_id,
+            InputStyle::PREEDIT_POSITION | InputStyle::STATUS_NOTHING,
+            Some(IM_NAME),
+        );
+        Self {
+            ime,
+            conn,
+            win,
+            server: Some(server),
+            xvfb,
+        }
+    }
+
+    /// Create the input context and wait until it exists.
+    pub fn create_ic(&mut self) {
+        self.ime.update_pos(self.win, 10, 20);
+        assert!(
+            self.pump(|ime, _| ime.ic_info().is_some()),
+            "no input context has been created"
+        );
+    }
+
+    /// What the running server received so far.
+    pub fn log(&self) -> MutexGuard<'_, ServerLog> {
+        self.server.as_ref().expect("no XIM server running").log()
+    }
+
+    /// Stop the server, as an IME server that quits.
+    pub fn stop_server(&mut self) {
+        self.server = None;
+    }
+
+    /// Start a new server after [`Setup::stop_server`].
+    pub fn start_server(&mut self, config: ServerConfig) {
+        self.server = Some(XimServer::start(&self.xvfb, config));
+    }
+
+    /// A key press or release of the key with keycode 38 (`a` in most layouts) in the window.
+    pub fn key_event(&self, press: bool) -> xcb::Event {
+        let root = self.conn.get_setup().roots().next().unwrap().root();
+        let event = xcb::x::KeyPressEvent::new(
+            38,
+            xcb::x::CURRENT_TIME,
+            root,
+            self.win,
+            Window::none(),
+            0,
+            0,
+            0,
+            0,
+            xcb::x::KeyButMask::empty(),
+            true,
+        );
+        if press {
+            xcb::Event::X(xcb::x::Event::KeyPress(event))
+        } else {
+            // key release events share the type of key press events and differ in the response
+            // type
+            unsafe { (*event.as_raw()).response_type = KEY_RELEASE };
+            xcb::Event::X(xcb::x::Event::KeyRelease(event))
+        }
+    }
+
+    /// Pass the events of the connection to the [`ImeClient`] until `done` returns `true`.
+    ///
+    /// `done` is passed what the running server received, which is empty while no server is
+    /// running. Return `false` if `done` still returns `false` after [`TIMEOUT`].
+    pub fn pump<F>(&mut self, mut done: F) -> bool
+    where
+        F: FnMut(&ImeClient, &ServerLog) -> bool,
+    {
+        let deadline = Instant::now() + TIMEOUT;
+        let no_server = ServerLog::default();
+        loop {
+            let finished = match &self.server {
+                Some(server) => done(&self.ime, &server.log()),
+                None => done(&self.ime, &no_server),
+            };
+            if finished {
+                return true;
+            }
+            self.conn.flush().unwrap();
+            match self.conn.poll_for_event() {
+                Ok(Some(event)) => {
+                    self.ime.process_event(&event);
+                }
+                Ok(None) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(1))
+                }
+                Ok(None) => return false,
+                Err(xcb::Error::Protocol(_)) => {}
+                Err(err) => panic!("lost the connection to Xvfb: {}", err),
+            }
+        }
+    }
+}
+
+/// Create an unmapped window selecting key events.
+fn create_window(conn: &xcb::Connection, screen_id: i32) -> Window {
+    let screen = conn.get_setup().roots().nth(screen_id as usize).unwrap();
+    let window = conn.generate_id();
+    conn.send_request(&xcb::x::CreateWindow {
+        depth: xcb::x::COPY_FROM_PARENT as u8,
+        wid: window,
+        parent: screen.root(),
+        x: 0,
+        y: 0,
+        width: 100,
+        height: 100,
+        border_width: 0,
+        class: xcb::x::WindowClass::InputOutput,
+        visual: screen.root_visual(),
+        value_list: &[xcb::x::Cw::EventMask(
+            EventMask::KEY_PRESS | EventMask::KEY_RELEASE,
+        )],
+    });
+    conn.flush().unwrap();
+    window
+}
@@ -0,0 +1,43 @@
+//! Decoding of compound text sent by IME servers that do not support UTF-8.
+
+use xcb_imdkit::Encoding;
+
+/// Designate ISO 8859-1 to the right half.
+const LATIN1_GR: &[u8] = b"\x1b-A";
+/// Designate GB 2312 to the right half.
+const GB2312_GR: &[u8] = b"\x1b$)A";
+
+fn compound_text(segments: &[&[u8]]) -> Vec<u8> {
+    segments.concat()
+}
+
+#[test]
+fn latin_and_gb2312_segments() {
+    // 你好 is C4E3 BAC3 in GB 2312, ä is E4 in ISO 8859-1
+    let input = compound_text(&[
+        b"abc",
+        GB2312_GR,
+        b"\xc4\xe3\xba\xc3",
+        LATIN1_GR,
+        b"\xe4",
+        b"xyz",
+    ]);
+    assert_eq!(
+        Encoding::CompoundText.decode(&input),
+        "abc\u{4f60}\u{597d}\u{e4}xyz"
+    );
+}
+
+#[test]
+fn segment_at_the_end() {
+    let input = compound_text(&[b"ab", GB2312_GR, b"\xc4\xe3\xba\xc3"]);
+    assert_eq!(Encoding::CompoundText.decode(&input), "ab\u{4f60}\u{597d}");
+    let input = compound_text(&[GB2312_GR, b"\xc4\xe3", LATIN1_GR, b"\xe4"]);
+    assert_eq!(Encoding::CompoundText.decode(&input), "\u{4f60}\u{e4}");
+}
+
+#[test]
+fn single_bytes() {
+    assert_eq!(Encoding::CompoundText.decode(b"a"), "a");
+    assert_eq!(Encoding::CompoundText.decode(b""), "");
+}
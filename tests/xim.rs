@@ -0,0 +1,190 @@
+//! Tests of the IME client against a minimal XIM server.
+//!
+//! The tests require `Xvfb` and are ignored by default, run them with
+//! `cargo test --test xim -- --ignored`.
+
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use common::{
+    card32, point, ServerConfig, ServerLog, Setup, KEY_PRESS, KEY_RELEASE, TIMEOUT, XIM_CONNECT,
+    XIM_CREATE_IC, XIM_FORWARD_EVENT,
+};
+use xcb::x::{EventMask, Window};
+use xcb::Xid;
+use xcb_imdkit::ImeState;
+
+#[test]
+#[ignore = "requires Xvfb"]
+fn key_press_commits_string() {
+    let mut setup = Setup::new(ServerConfig {
+        commit: "你好".to_owned(),
+        ..ServerConfig::default()
+    });
+    let commits = Rc::new(RefCell::new(Vec::new()));
+    {
+        let commits = commits.clone();
+        setup.ime.set_commit_string_cb(move |win, text| {
+            commits.borrow_mut().push((win, text.to_owned()))
+        });
+    }
+
+    setup.create_ic();
+    let key = setup.key_event(true);
+    assert!(setup.ime.process_event(&key));
+    assert!(
+        setup.pump(|_, _| !commits.borrow().is_empty()),
+        "nothing has been committed"
+    );
+
+    assert_eq!(*commits.borrow(), [(setup.win, "你好".to_owned())]);
+    assert!(setup.log().requests.contains(&XIM_FORWARD_EVENT));
+    assert_eq!(setup.log().forwarded, [KEY_PRESS]);
+}
+
+#[test]
+#[ignore = "requires Xvfb"]
+fn server_event_mask_is_respected() {
+    let mut setup = Setup::new(ServerConfig {
+        forward_event_mask: Some(EventMask::KEY_PRESS.bits()),
+        ..ServerConfig::default()
+    });
+
+    setup.create_ic();
+    assert!(
+        setup.pump(|ime, _| ime.forwarded_event_mask() == EventMask::KEY_PRESS.bits()),
+        "the event mask of the server has not been applied"
+    );
+    let release = setup.key_event(false);
+    assert!(!setup.ime.process_event(&release));
+    let press = setup.key_event(true);
+    assert!(setup.ime.process_event(&press));
+    // the key press is sent after the key release, which would have arrived first
+    assert!(
+        setup.pump(|_, log| !log.forwarded.is_empty()),
+        "the key press has not been forwarded"
+    );
+
+    assert_eq!(setup.ime.stats().forwarded, 1);
+    let log = setup.log();
+    assert!(
+        !log.forwarded.contains(&KEY_RELEASE),
+        "the key release has been forwarded"
+    );
+    assert_eq!(log.forwarded, [KEY_PRESS]);
+}
+
+#[test]
+#[ignore = "requires Xvfb"]
+fn attributes_are_reapplied_after_reconnect() {
+    let mut setup = Setup::new(ServerConfig::default());
+
+    setup.create_ic();
+    setup.ime.set_preedit_colors(0x00ff00, 0x000080);
+    setup.ime.update_pos(setup.win, 30, 40);
+    assert!(
+        setup
+            .pump(|_, log| log.ic_values.get("preeditAttributes/spotLocation")
+                == Some(&point(30, 40))),
+        "the spot location has not been updated"
+    );
+
+    setup.stop_server();
+    assert!(
+        setup.pump(|ime, _| ime.state() == ImeState::Disconnected),
+        "the lost IME server has not been noticed"
+    );
+    setup.start_server(ServerConfig::default());
+    // reconnect without passing the attributes again
+    let key = setup.key_event(true);
+    setup.ime.process_event(&key);
+    assert!(
+        setup.pump(|ime, _| ime.ic_info().is_some()),
+        "no input context has been created after reconnecting"
+    );
+    assert_eq!(setup.ime.state(), ImeState::Open);
+    assert_eq!(setup.ime.stats().disconnects, 1);
+    assert_eq!(setup.ime.stats().opens, 2);
+    let requests = setup.log().requests.clone();
+    assert!(requests.contains(&XIM_CONNECT) && requests.contains(&XIM_CREATE_IC));
+    assert_attributes(&setup.log(), setup.win);
+
+    setup.log().ic_values.clear();
+    assert!(setup.ime.reapply_attrs());
+    assert!(
+        setup.pump(|_, log| log.ic_values.contains_key("preeditAttributes/spotLocation")),
+        "the attributes have not been reapplied"
+    );
+    assert_attributes(&setup.log(), setup.win);
+}
+
+/// Check that the server received the attributes set by `attributes_are_reapplied_after_reconnect`.
+fn assert_attributes(log: &ServerLog, win: Window) {
+    let values = &log.ic_values;
+    let win = card32(win.resource_id());
+    assert_eq!(values.get("clientWindow"), Some(&win));
+    assert_eq!(values.get("focusWindow"), Some(&win));
+    assert_eq!(
+        values.get("preeditAttributes/spotLocation"),
+        Some(&point(30, 40))
+    );
+    assert_eq!(
+        values.get("preeditAttributes/foreground"),
+        Some(&card32(0x00ff00))
+    );
+    assert_eq!(
+        values.get("preeditAttributes/background"),
+        Some(&card32(0x000080))
+    );
+}
+
+#[test]
+#[ignore = "requires Xvfb"]
+fn drain_until_idle_waits_for_the_server() {
+    // each reply arrives well within the idle period, but all of them take longer
+    let mut setup = Setup::new(ServerConfig {
+        reply_delay: Duration::from_millis(10),
+        ..ServerConfig::default()
+    });
+
+    setup.ime.update_pos(setup.win, 10, 20);
+    assert!(setup.ime.drain_until_idle(TIMEOUT));
+
+    assert!(
+        setup.ime.ic_info().is_some(),
+        "returned while the server was still creating the input context"
+    );
+}
+
+#[test]
+#[ignore = "requires Xvfb"]
+fn xim_messages_are_not_deferred() {
+    let mut setup = Setup::new(ServerConfig {
+        commit: "a".to_owned(),
+        ..ServerConfig::default()
+    });
+
+    // the whole exchange with the server goes through the waiting methods
+    setup.ime.update_pos(setup.win, 10, 20);
+    assert!(setup.ime.drain_until_idle(TIMEOUT));
+    assert!(
+        setup.ime.ic_info().is_some(),
+        "no input context has been created"
+    );
+    let key = setup.key_event(true);
+    assert!(setup.ime.process_event(&key));
+    let commit = setup.ime.wait_for_commit(TIMEOUT);
+    assert_eq!(commit, Some((setup.win, "a".to_owned())));
+
+    let deferred = setup.ime.take_deferred_events();
+    assert!(
+        !deferred
+            .iter()
+            .any(|event| matches!(event, xcb::Event::X(xcb::x::Event::ClientMessage(_)))),
+        "XIM messages have been deferred: {:?}",
+        deferred
+    );
+}